@@ -5,6 +5,9 @@ use openraft::Config;
 use crate::StorageNodeId;
 use crate::StorageNodeRaft;
 use crate::store::StorageNodeFileStore;
+use crate::store::backend::{MultiDiskBackend, SliceBackend};
+use crate::store::capacity::NodeCapacity;
+use crate::store::scrub::ScrubStats;
 
 // Representation of an application state. This struct can be shared around to share
 // instances of raft, store and more.
@@ -13,5 +16,10 @@ pub struct StorageNode {
     pub addr: String,
     pub raft: StorageNodeRaft,
     pub store: Arc<StorageNodeFileStore>,
+    pub backend: Arc<dyn SliceBackend>,
+    /// Data directories of the directory and segment backends, None for the others.
+    pub disks: Option<Arc<MultiDiskBackend>>,
+    pub capacity: Arc<NodeCapacity>,
     pub config: Arc<Config>,
+    pub scrub_stats: Arc<ScrubStats>,
 }
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+
+use openraft::{Config, SnapshotPolicy};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::ARGS;
+
+/// Bytes of snapshot data sent per request when neither the command line nor the config file says.
+const DEFAULT_SNAPSHOT_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Room in the default `install_snapshot_timeout`, beside the time a chunk is throttled,
+/// to send the chunk and for the node to take it.
+const SNAPSHOT_CHUNK_ALLOWANCE: u64 = 1000;
+
+/// Milliseconds a snapshot chunk of `chunk_size` bytes is held back to stay under `--snapshot-bytes-per-second`.
+fn chunk_throttle_millis(chunk_size: u64) -> u64 {
+    match ARGS.snapshot_bytes_per_second {
+        0 => 0,
+        rate => chunk_size.saturating_mul(1000).saturating_add(rate - 1) / rate,
+    }
+}
+
+/// The `[raft]` table of `--config-file`. Times are in milliseconds. Options given on the command line win.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RaftSettings {
+    heartbeat_interval: Option<u64>,
+    election_timeout_min: Option<u64>,
+    election_timeout_max: Option<u64>,
+    install_snapshot_timeout: Option<u64>,
+    max_payload_entries: Option<u64>,
+    replication_lag_threshold: Option<u64>,
+    snapshot_logs_since_last: Option<u64>,
+    snapshot_chunk_size: Option<u64>,
+    max_applied_log_to_keep: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    raft: RaftSettings,
+}
+
+fn invalid(detail: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, detail)
+}
+
+fn load_config_file() -> io::Result<ConfigFile> {
+    match &ARGS.config_file {
+        Some(path) => {
+            let text = fs::read_to_string(path)?;
+            toml::from_str(&text).map_err(|e| invalid(format!("config file {}: {}", path, e)))
+        }
+        None => Ok(ConfigFile::default()),
+    }
+}
+
+/// Raft configuration of this node: the command line, then `--config-file`, then the openraft defaults.
+/// Refuses settings raft would not work with, before the node joins its group.
+pub fn raft_config() -> io::Result<Config> {
+    let file = load_config_file()?.raft;
+    let defaults = Config::default();
+    let snapshot_chunk_size = ARGS.snapshot_chunk_size.or(file.snapshot_chunk_size).unwrap_or(DEFAULT_SNAPSHOT_CHUNK_SIZE);
+    // Sending a chunk, throttled, has to fit in the timeout raft puts on it.
+    let install_snapshot_timeout = defaults.install_snapshot_timeout
+        .max(chunk_throttle_millis(snapshot_chunk_size).saturating_add(SNAPSHOT_CHUNK_ALLOWANCE));
+    let config = Config {
+        heartbeat_interval: ARGS.heartbeat_interval.or(file.heartbeat_interval).unwrap_or(defaults.heartbeat_interval),
+        election_timeout_min: ARGS.election_timeout_min.or(file.election_timeout_min).unwrap_or(defaults.election_timeout_min),
+        election_timeout_max: ARGS.election_timeout_max.or(file.election_timeout_max).unwrap_or(defaults.election_timeout_max),
+        install_snapshot_timeout: ARGS.install_snapshot_timeout.or(file.install_snapshot_timeout).unwrap_or(install_snapshot_timeout),
+        max_payload_entries: ARGS.max_payload_entries.or(file.max_payload_entries).unwrap_or(defaults.max_payload_entries),
+        replication_lag_threshold: ARGS.replication_lag_threshold.or(file.replication_lag_threshold).unwrap_or(defaults.replication_lag_threshold),
+        snapshot_policy: match ARGS.snapshot_logs_since_last.or(file.snapshot_logs_since_last) {
+            Some(logs) => SnapshotPolicy::LogsSinceLast(logs),
+            None => defaults.snapshot_policy.clone(),
+        },
+        snapshot_max_chunk_size: snapshot_chunk_size,
+        max_applied_log_to_keep: ARGS.max_applied_log_to_keep.or(file.max_applied_log_to_keep).unwrap_or(defaults.max_applied_log_to_keep),
+        ..defaults
+    };
+
+    // A leader must be heard from well within the shortest election timeout, or followers keep electing.
+    let twice_heartbeat = config.heartbeat_interval.checked_mul(2);
+    if config.heartbeat_interval == 0 || twice_heartbeat.map_or(true, |twice| twice > config.election_timeout_min) {
+        return Err(invalid(format!(
+            "heartbeat interval {}ms should be positive and at most half the minimum election timeout {}ms",
+            config.heartbeat_interval, config.election_timeout_min,
+        )));
+    }
+    if config.snapshot_max_chunk_size == 0 {
+        return Err(invalid("snapshot chunk size should be positive".to_string()));
+    }
+    // Raft gives up on a chunk after `install_snapshot_timeout`, a chunk throttled for longer never gets through.
+    let chunk_millis = chunk_throttle_millis(config.snapshot_max_chunk_size);
+    if chunk_millis >= config.install_snapshot_timeout {
+        return Err(invalid(format!(
+            "a snapshot chunk of {} bytes takes {}ms at {} bytes per second, more than the install snapshot timeout {}ms",
+            config.snapshot_max_chunk_size, chunk_millis, ARGS.snapshot_bytes_per_second, config.install_snapshot_timeout,
+        )));
+    }
+    config.validate().map_err(|e| invalid(format!("invalid raft config: {}", e)))
+}
+
+/// The raft configuration as served on `/config`.
+pub fn describe(config: &Config) -> serde_json::Value {
+    let SnapshotPolicy::LogsSinceLast(snapshot_logs_since_last) = config.snapshot_policy;
+    json!({
+        "cluster_name": config.cluster_name,
+        "heartbeat_interval": config.heartbeat_interval,
+        "election_timeout_min": config.election_timeout_min,
+        "election_timeout_max": config.election_timeout_max,
+        "install_snapshot_timeout": config.install_snapshot_timeout,
+        "max_payload_entries": config.max_payload_entries,
+        "replication_lag_threshold": config.replication_lag_threshold,
+        "snapshot_logs_since_last": snapshot_logs_since_last,
+        "snapshot_chunk_size": config.snapshot_max_chunk_size,
+        "max_applied_log_to_keep": config.max_applied_log_to_keep,
+    })
+}
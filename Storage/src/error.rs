@@ -0,0 +1,65 @@
+use std::io;
+
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use openraft::error::Fatal;
+use openraft::StorageError;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::StorageNodeId;
+
+/// Errors of a storage node, as the http layer reports them: a json body with the `error` kind and a `detail`.
+#[derive(Debug, Error)]
+pub enum NodeError {
+    /// The raft log, the vote or the state machine could not be read or written, raft stops on it.
+    #[error("raft storage failed: {0}")]
+    Storage(#[from] StorageError<StorageNodeId>),
+
+    /// A record of the raft log does not decode: corrupted on disk, or written by a version this node does not read.
+    #[error("raft log record {index} is corrupted: {source}")]
+    CorruptLogRecord { index: u64, source: io::Error },
+
+    /// Raft is not running any more, e.g. after a storage failure.
+    #[error("raft stopped: {0}")]
+    RaftStopped(String),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+impl NodeError {
+    fn kind(&self) -> &'static str {
+        match self {
+            NodeError::Storage(_) => "storage",
+            NodeError::CorruptLogRecord { .. } => "corrupt-log-record",
+            NodeError::RaftStopped(_) => "raft-stopped",
+            NodeError::Io(_) => "io",
+        }
+    }
+}
+
+impl From<Fatal<StorageNodeId>> for NodeError {
+    fn from(fatal: Fatal<StorageNodeId>) -> Self {
+        match fatal {
+            Fatal::StorageError(err) => NodeError::Storage(err),
+            fatal => NodeError::RaftStopped(fatal.to_string()),
+        }
+    }
+}
+
+impl ResponseError for NodeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            NodeError::RaftStopped(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": self.kind(),
+            "detail": self.to_string(),
+        }))
+    }
+}
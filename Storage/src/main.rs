@@ -2,6 +2,8 @@
 #![feature(iter_intersperse)]
 
 mod app;
+mod config;
+mod error;
 mod network;
 mod store;
 pub mod testing;
@@ -15,6 +17,8 @@ use crate::network::StorageNodeNetwork;
 use crate::store::StorageNodeFileStore;
 use crate::store::StorageNodeRequest;
 use crate::store::StorageNodeResponse;
+use crate::store::backend::SliceBackendKind;
+use crate::store::backend::compression::CompressionCodec;
 
 pub type StorageNodeId = u64;
 
@@ -34,6 +38,16 @@ pub struct Args {
     port: u16,
     #[clap(short, long, default_value = "/tmp/storage")]
     storage_location: String,
+    #[clap(long = "data-dir", multiple_occurrences = true)] // Slice directory as <path>[:<weight>], repeat for several disks. storage_location when none.
+    data_dirs: Vec<String>,
+    #[clap(long, default_value_t = 1000)] // A data directory whose operations take this long on average is slow.
+    slow_disk_millis: u64,
+    #[clap(long, default_value_t = 90)] // Percent of a storage location in use above which client writes are refused.
+    high_watermark: u8,
+    #[clap(long, default_value_t = 80)] // Percent of a storage location in use below which client writes are taken again.
+    low_watermark: u8,
+    #[clap(long, default_value_t = 3600)] // Seconds between two counts of the slices of each data directory.
+    capacity_scan_interval: u64,
     #[clap(short, long)]
     monitor_addr: String, //The ip port of monitor server.
     #[clap(long, default_value_t = 10)]
@@ -44,6 +58,54 @@ pub struct Args {
     node_addr: String, //The ip address for others to connect to this node.
     #[clap(long, default_value_t = 1<<16)] // 64KB of payload per request
     payload_size: usize,
+    #[clap(long)] // TOML file with a [raft] table of the raft options below, these options win over it.
+    config_file: Option<String>,
+    #[clap(long)] // Milliseconds between two heartbeats of the leader.
+    heartbeat_interval: Option<u64>,
+    #[clap(long)] // Milliseconds without a leader before a follower starts an election, at least.
+    election_timeout_min: Option<u64>,
+    #[clap(long)] // Same, at most; each election timeout is picked between the two.
+    election_timeout_max: Option<u64>,
+    #[clap(long)] // Milliseconds for a node to take a snapshot chunk.
+    install_snapshot_timeout: Option<u64>,
+    #[clap(long)] // Log entries sent to a follower per request.
+    max_payload_entries: Option<u64>,
+    #[clap(long)] // Entries a follower may lag behind before it is sent a snapshot.
+    replication_lag_threshold: Option<u64>,
+    #[clap(long)] // Applied log entries after which a new snapshot is built.
+    snapshot_logs_since_last: Option<u64>,
+    #[clap(long)] // Applied log entries kept when the log is purged after a snapshot.
+    max_applied_log_to_keep: Option<u64>,
+    #[clap(long)] // Bytes of snapshot data sent per request, 1MB unless the config file says.
+    snapshot_chunk_size: Option<u64>,
+    #[clap(long, default_value_t = 32<<20)] // 32MB/s of snapshot data sent to each node catching up, 0 means unlimited.
+    snapshot_bytes_per_second: u64,
+    #[clap(long, arg_enum, default_value = "directory")] // Where slice content is stored.
+    slice_backend: SliceBackendKind,
+    #[clap(long, arg_enum, default_value = "none")] // Codec compressing slices at rest.
+    compression: CompressionCodec,
+    #[clap(long, default_value_t = 3)] // Compression level, used by zstd.
+    compression_level: i32,
+    #[clap(long, default_value_t = 1<<16)] // Largest slice the sled backend accepts.
+    sled_max_slice_size: usize,
+    #[clap(long, default_value_t = 1<<16)] // Slices smaller than this are packed into segments by the segment backend.
+    segment_threshold: usize,
+    #[clap(long, default_value_t = 1<<28)] // A segment is sealed once it reaches 256MB.
+    segment_size: u64,
+    #[clap(long, default_value_t = 600)] // Seconds between two segment compaction passes.
+    segment_compaction_interval: u64,
+    #[clap(long, default_value_t = 86400)] // Seconds between two scrub passes.
+    scrub_interval: u64,
+    #[clap(long, default_value_t = 16<<20)] // 16MB/s of slice reads for the scrubber, 0 means unlimited.
+    scrub_bytes_per_second: u64,
+    #[clap(long, default_value_t = 3600)] // Seconds a blob or shard nothing refers to is kept before the scrubber deletes it.
+    gc_grace_period: u64,
+    #[clap(long, default_value_t = 4)] // Data shards of erasure coded slices, unless the writer asks for others.
+    erasure_data_shards: usize,
+    #[clap(long, default_value_t = 2)] // Parity shards of erasure coded slices, unless the writer asks for others.
+    erasure_parity_shards: usize,
+    #[clap(long)] // JSON file of hex master keys, enables encryption of slices and raft log entries at rest.
+    master_key_file: Option<String>,
 }
 
 pub static ARGS: SyncLazy<Args> = SyncLazy::new(|| {
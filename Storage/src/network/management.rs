@@ -8,15 +8,18 @@ use actix_web::web;
 use actix_web::web::Data;
 use actix_web::Responder;
 use openraft::error::Infallible;
-use openraft::{EntryPayload, Node};
+use openraft::{EntryPayload, Node, State};
 use openraft::raft::ClientWriteRequest;
 use openraft::RaftMetrics;
 use serde_json::json;
 use web::Json;
 
 use crate::app::StorageNode;
+use crate::config::describe;
+use crate::error::NodeError;
 use crate::{ARGS, StorageNodeId, StorageNodeRequest};
 use crate::StorageRaftTypeConfig;
+use crate::store::peer;
 
 // --- Cluster management
 
@@ -90,6 +93,94 @@ pub async fn metrics(app: Data<StorageNode>) -> actix_web::Result<impl Responder
     Ok(Json(res))
 }
 
+/// Get the raft configuration this node runs with, after the command line and the config file.
+#[get("/config")]
+pub async fn config(app: Data<StorageNode>) -> actix_web::Result<impl Responder> {
+    Ok(Json(describe(&app.config)))
+}
+
+/// Get storage level metrics of this node, e.g. the progress and findings of the scrubber.
+#[get("/node-metrics")]
+pub async fn node_metrics(app: Data<StorageNode>) -> actix_web::Result<impl Responder> {
+    let dedup = app.store.state_machine.read().await.dedup_metrics().map_err(NodeError::from)?;
+    Ok(Json(json!({
+        "scrub": app.scrub_stats.metrics(),
+        "dedup": dedup,
+        "health": app.store.health.metrics(),
+        "capacity": app.capacity.metrics(),
+        "disks": app.disks.as_ref().map(|disks| disks.metrics()),
+    })))
+}
+
+/// Hands leadership over when this node's storage turned read-only while it leads, by turning itself into
+/// a learner: it keeps receiving the log and raft keeps running, but it neither votes nor campaigns.
+/// `rejoin` makes it a voter again once its storage takes writes. A degraded node (a slow or full disk among others)
+/// keeps leading, and so does the only voter of a group.
+pub async fn step_down(app: &StorageNode) {
+    if app.raft.metrics().borrow().state != State::Leader {
+        return;
+    }
+    let voters: BTreeSet<StorageNodeId> = {
+        let state_machine = app.store.state_machine.read().await;
+        state_machine.last_membership.membership.all_members().iter().cloned().filter(|id| *id != app.id).collect()
+    };
+    if voters.is_empty() {
+        tracing::warn!("storage is read-only, but this node is the only voter and stays leader");
+        return;
+    }
+    // Recorded first, so that a node stopped before the change is committed still asks to rejoin.
+    if let Err(err) = app.store.set_stepped_down(true) {
+        tracing::error!("failed to record stepping down: {}", err);
+    }
+    tracing::warn!("storage is read-only, stepping down as leader in favour of {:?}", voters);
+    if let Err(err) = app.raft.change_membership(voters, true, true).await {
+        tracing::error!("failed to step down as leader: {}", err);
+    }
+}
+
+/// Asks the leader to make this node a voter again, once it stepped down and its storage takes writes again.
+/// Forgets having stepped down when the membership lists it as a voter.
+pub async fn rejoin(app: &StorageNode) {
+    match app.store.stepped_down() {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            tracing::error!("failed to read whether this node stepped down: {}", err);
+            return;
+        }
+    }
+    let leader = app.raft.metrics().borrow().current_leader;
+    let (addr, voters) = {
+        let state_machine = app.store.state_machine.read().await;
+        let membership = &state_machine.last_membership.membership;
+        if membership.all_members().contains(&app.id) {
+            drop(state_machine);
+            tracing::info!("this node is a voter again");
+            if let Err(err) = app.store.set_stepped_down(false) {
+                tracing::error!("failed to record rejoining the voters: {}", err);
+            }
+            return;
+        }
+        let addr = match leader.filter(|leader| *leader != app.id).and_then(|leader| membership.get_node(&leader)) {
+            Some(node) => node.addr.clone(),
+            None => return,
+        };
+        let mut voters = membership.all_members().clone();
+        voters.insert(app.id);
+        (addr, voters)
+    };
+    tracing::info!("storage takes writes again, asking {} to make this node a voter", addr);
+    let resp = peer::client().post(format!("http://{}/change-membership", addr)).json(&voters).send().await;
+    match resp {
+        Ok(resp) => match resp.json::<serde_json::Value>().await {
+            Ok(body) if body.get("Err").is_none() => {}
+            Ok(body) => tracing::warn!("{} refused to make this node a voter: {}", addr, body["Err"]),
+            Err(err) => tracing::warn!("failed to ask {} to make this node a voter: {}", addr, err),
+        },
+        Err(err) => tracing::warn!("failed to ask {} to make this node a voter: {}", addr, err),
+    }
+}
+
 #[get("/health")]
 async fn get_health() -> impl Responder {
     HttpResponse::Ok().body("I'm healthy.")
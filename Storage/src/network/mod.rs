@@ -3,7 +3,7 @@ use std::sync::Arc;
 use actix_web::{App, HttpServer, web};
 use actix_web::web::Data;
 use async_trait::async_trait;
-use openraft::{Config, Node, Raft};
+use openraft::{Node, Raft};
 use openraft::error::{AppendEntriesError};
 use openraft::error::InstallSnapshotError;
 use openraft::error::NetworkError;
@@ -23,11 +23,24 @@ use serde::Serialize;
 
 use crate::{ARGS, StorageNodeId, StorageNodeFileStore};
 use crate::app::StorageNode;
+use crate::config::raft_config;
+use crate::store::backend::open_slice_backend;
+use crate::store::keyring::load_keyring;
+use crate::store::capacity::{NodeCapacity, Watermarks};
+use crate::store::codec;
+use crate::store::get_sled_db;
+use crate::store::health::HealthState;
+use crate::store::health::NodeHealth;
+use crate::store::peer;
+use crate::store::peer::throttle;
+use crate::store::scrub::{run_scrubber, ScrubStats};
 use crate::StorageRaftTypeConfig;
+use crate::network::snapshot::{encode_chunk, SnapshotProgress};
 
 pub mod slice;
 pub mod raft;
 pub mod management;
+pub mod snapshot;
 
 pub struct StorageNodeNetwork {}
 
@@ -73,14 +86,48 @@ impl StorageNodeNetwork {
         let addr = target_node.map(|x| &x.addr).unwrap();
 
         let url = format!("http://{}/{}", addr, uri);
-        let client = reqwest::Client::new();
+        let client = peer::client();
 
-        let resp = client.post(url).json(&req).send().await.map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+        // Requests are binary, the entries they carry would take several times their size as JSON.
+        // Responses are small and stay JSON.
+        let body = codec::encode(&req).map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+        let resp = client.post(url)
+                         .header(reqwest::header::CONTENT_TYPE, codec::BINARY_CONTENT_TYPE)
+                         .body(body)
+                         .send().await
+                         .and_then(|resp| resp.error_for_status())
+                         .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
 
         let res: Result<Resp, Err> = resp.json().await.map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
 
         res.map_err(|e| RPCError::RemoteError(RemoteError::new(target, e)))
     }
+
+    /// Posts a chunk of a snapshot as a binary body, see `snapshot::encode_chunk`.
+    pub async fn send_snapshot_chunk(
+        &self,
+        target: StorageNodeId,
+        target_node: Option<&Node>,
+        req: &InstallSnapshotRequest<StorageRaftTypeConfig>,
+    ) -> Result<InstallSnapshotResponse<StorageNodeId>, RPCError<StorageNodeId, InstallSnapshotError<StorageNodeId>>> {
+        let addr = target_node.map(|x| &x.addr).unwrap();
+
+        let url = format!("http://{}/raft-snapshot", addr);
+        let client = peer::client();
+        let body = encode_chunk(req).map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+
+        let resp = client.post(url)
+                         .header(reqwest::header::CONTENT_TYPE, codec::BINARY_CONTENT_TYPE)
+                         .body(body)
+                         .send().await
+                         .and_then(|resp| resp.error_for_status())
+                         .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+
+        let res: Result<InstallSnapshotResponse<StorageNodeId>, InstallSnapshotError<StorageNodeId>> =
+            resp.json().await.map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+
+        res.map_err(|e| RPCError::RemoteError(RemoteError::new(target, e)))
+    }
 }
 
 // NOTE: This could be implemented also on `Arc<ExampleNetwork>`, but since it's empty, implemented directly.
@@ -93,6 +140,7 @@ impl RaftNetworkFactory<StorageRaftTypeConfig> for StorageNodeNetwork {
             owner: StorageNodeNetwork {},
             target,
             target_node: node.cloned(),
+            snapshot_progress: None,
         }
     }
 }
@@ -101,6 +149,7 @@ pub struct StorageNodeNetworkConnection {
     owner: StorageNodeNetwork,
     target: StorageNodeId,
     target_node: Option<Node>,
+    snapshot_progress: Option<SnapshotProgress>,
 }
 
 #[async_trait]
@@ -118,7 +167,28 @@ impl RaftNetwork<StorageRaftTypeConfig> for StorageNodeNetworkConnection {
         req: InstallSnapshotRequest<StorageRaftTypeConfig>,
     ) -> Result<InstallSnapshotResponse<StorageNodeId>, RPCError<StorageNodeId, InstallSnapshotError<StorageNodeId>>>
     {
-        self.owner.send_rpc(self.target, self.target_node.as_ref(), "raft-snapshot", req).await
+        if let Some(response) = self.snapshot_progress.as_ref().and_then(|progress| progress.acknowledged(&req)) {
+            return Ok(response);
+        }
+        throttle(req.data.len(), ARGS.snapshot_bytes_per_second).await;
+
+        // Raft bounds this call with `install_snapshot_timeout` and sends the snapshot again after a failure,
+        // skipping what the node acknowledged already: a failed chunk is not retried here.
+        match self.owner.send_snapshot_chunk(self.target, self.target_node.as_ref(), &req).await {
+            Ok(response) => {
+                self.snapshot_progress = SnapshotProgress::after(&req, &response);
+                Ok(response)
+            }
+            // The node gave up on this snapshot, e.g. it restarted, it is sent from the start again.
+            Err(err @ RPCError::RemoteError(_)) => {
+                self.snapshot_progress = None;
+                Err(err)
+            }
+            Err(err) => {
+                tracing::warn!("failed to send snapshot chunk at offset {} to node {}: {}", req.offset, self.target, err);
+                Err(err)
+            }
+        }
     }
 
     async fn send_vote(
@@ -131,7 +201,7 @@ impl RaftNetwork<StorageRaftTypeConfig> for StorageNodeNetworkConnection {
 
 pub async fn init_httpserver() -> std::io::Result<()> {
     // Create a configuration for the raft instance.
-    let config = Arc::new(Config::default().validate().unwrap());
+    let config = Arc::new(raft_config()?);
     /*
 
     let db: sled::Db = sled::open("try_my_db").unwrap();
@@ -148,7 +218,12 @@ pub async fn init_httpserver() -> std::io::Result<()> {
 
      */
 
-    let res = StorageNodeFileStore::open_create(None, Some(()));
+    let db = get_sled_db()?;
+    let keyring = load_keyring()?;
+    let (backend, disks) = open_slice_backend(&db, keyring.clone())?;
+    let health = Arc::new(NodeHealth::new(disks.clone()));
+    let capacity = Arc::new(NodeCapacity::new(disks.clone(), &ARGS.storage_location, Watermarks::from_args()?));
+    let res = StorageNodeFileStore::open_create(&db, backend.clone(), keyring, health)?;
 
     let store = Arc::new(res);
     // Create a instance of where the Raft data will be stored.
@@ -160,6 +235,9 @@ pub async fn init_httpserver() -> std::io::Result<()> {
     // Create a local raft instance.
     let raft = Raft::new(ARGS.node_id, config.clone(), network, store.clone());
 
+    let scrub_stats = Arc::new(ScrubStats::default());
+    tokio::spawn(run_scrubber(store.clone(), scrub_stats.clone()));
+
     // Create an application that will store all the instances created above, this will
     // be later used on the actix-web services.
     let app = Data::new(StorageNode {
@@ -167,7 +245,11 @@ pub async fn init_httpserver() -> std::io::Result<()> {
         addr: ARGS.node_addr.clone(),
         raft,
         store,
+        backend,
+        disks,
+        capacity,
         config,
+        scrub_stats,
     });
 
 
@@ -177,18 +259,28 @@ pub async fn init_httpserver() -> std::io::Result<()> {
         let metrics = app1.raft.metrics().borrow().clone();
         let now_state: String = if metrics.current_term == 0 { "ready".into() } else { "serving".into() };
         let now_role = metrics.state.clone();
+        let health = app1.store.health.state();
+        let capacity = app1.capacity.metrics();
+        let app = app1.clone();
 
 
         async move {
+            if health == HealthState::ReadOnly {
+                management::step_down(&app).await;
+            } else {
+                management::rejoin(&app).await;
+            }
             let json_body = json!({
   "Status": now_state,
   "NodeId": ARGS.node_id.to_string(),
   "Role": now_role,
   "Addr": ARGS.node_addr,
   "Group": "-1",
-  "NodemapVersion": 1
+  "NodemapVersion": 1,
+  "Health": health.as_str(),
+  "Capacity": capacity
 });
-            let client = reqwest::Client::new();
+            let client = peer::client();
             client.post(format!("http://{}/heartbeat", ARGS.monitor_addr))
                   .body(json_body.to_string()).send().await;
         }
@@ -198,17 +290,31 @@ pub async fn init_httpserver() -> std::io::Result<()> {
             .app_data(app.clone())
             .app_data(web::PayloadConfig::new(ARGS.payload_size))
             // raft internal RPC
-            .service(raft::append)
-            .service(raft::snapshot)
+            .service(web::resource("/raft-append")
+                .app_data(web::PayloadConfig::new(raft::max_append_size(&app.config)))
+                .route(web::post().to(raft::append)))
+            .service(web::resource("/raft-snapshot")
+                .app_data(web::PayloadConfig::new(snapshot::max_request_size(&app.config)))
+                .route(web::post().to(raft::snapshot)))
             .service(raft::vote)
             // admin API
             .service(management::init)
             .service(management::add_learner)
             .service(management::change_membership)
             .service(management::metrics)
+            .service(management::node_metrics)
+            .service(management::config)
             // application API
             .service(slice::get_slice)
+            .service(slice::head_slice)
             .service(slice::put_slice)
+            .service(slice::delete_slice)
+            .service(slice::delete_slices)
+            .service(slice::list_slices)
+            .service(slice::get_shard)
+            .service(slice::put_shard)
+            .service(slice::get_blob)
+            .service(slice::put_blob)
     })
         .bind((ARGS.listen_addr.clone(), ARGS.port))?
         .run()
@@ -1,38 +1,56 @@
+use actix_web::error::ErrorBadRequest;
 use actix_web::post;
 use actix_web::Responder;
 use actix_web::web;
 use actix_web::web::Data;
+use openraft::Config;
 use openraft::raft::AppendEntriesRequest;
-use openraft::raft::InstallSnapshotRequest;
 use openraft::raft::VoteRequest;
 use web::Json;
 
 use crate::app::StorageNode;
-use crate::StorageNodeId;
+use crate::network::snapshot::decode_chunk;
+use crate::store::codec;
+use crate::{ARGS, StorageNodeId};
 use crate::StorageRaftTypeConfig;
 
 // --- Raft communication
 
+/// Room for the log id and framing of an entry, beside the slice it carries.
+const ENTRY_OVERHEAD: usize = 1 << 10;
+
+/// Largest body of a `/raft-append` request: as many entries as raft sends at once,
+/// each carrying a slice as large as clients may send.
+pub fn max_append_size(config: &Config) -> usize {
+    config.max_payload_entries as usize * (ARGS.payload_size + ENTRY_OVERHEAD)
+}
+
+// Requests are encoded by `codec`, JSON from nodes of earlier versions is read as well.
+
 #[post("/raft-vote")]
-pub async fn vote(app: Data<StorageNode>, req: Json<VoteRequest<StorageNodeId>>) -> actix_web::Result<impl Responder> {
-    let res = app.raft.vote(req.0).await;
+pub async fn vote(app: Data<StorageNode>, body: web::Bytes) -> actix_web::Result<impl Responder> {
+    let req: VoteRequest<StorageNodeId> = codec::decode(&body).map_err(ErrorBadRequest)?;
+    let res = app.raft.vote(req).await;
     Ok(Json(res))
 }
 
-#[post("/raft-append")]
+/// Served at `/raft-append` with a payload limit of its own, see `max_append_size`.
 pub async fn append(
     app: Data<StorageNode>,
-    req: Json<AppendEntriesRequest<StorageRaftTypeConfig>>,
+    body: web::Bytes,
 ) -> actix_web::Result<impl Responder> {
-    let res = app.raft.append_entries(req.0).await;
+    let req: AppendEntriesRequest<StorageRaftTypeConfig> = codec::decode(&body).map_err(ErrorBadRequest)?;
+    let res = app.raft.append_entries(req).await;
     Ok(Json(res))
 }
 
-#[post("/raft-snapshot")]
+/// Chunks of snapshots come as binary bodies, see `snapshot::encode_chunk`.
+/// Served at `/raft-snapshot` with a payload limit of its own, chunks are larger than other requests.
 pub async fn snapshot(
     app: Data<StorageNode>,
-    req: Json<InstallSnapshotRequest<StorageRaftTypeConfig>>,
+    body: web::Bytes,
 ) -> actix_web::Result<impl Responder> {
-    let res = app.raft.install_snapshot(req.0).await;
+    let req = decode_chunk(&body).map_err(ErrorBadRequest)?;
+    let res = app.raft.install_snapshot(req).await;
     Ok(Json(res))
 }
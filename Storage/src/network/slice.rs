@@ -1,56 +1,570 @@
-use actix_web::{get, HttpRequest, HttpResponse, put, Responder, web};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_files::{HttpRange, NamedFile};
+use actix_web::{delete, get, head, HttpRequest, HttpResponse, mime, post, put, ResponseError, web};
 use actix_web::http::header;
 use openraft::EntryPayload;
 use openraft::error::ClientWriteError;
-use openraft::raft::ClientWriteRequest;
+use openraft::raft::{ClientWriteRequest, ClientWriteResponse};
+use serde::Deserialize;
+use serde_json::json;
+use web::Json;
 
 use crate::app::StorageNode;
-use crate::{StorageNodeRequest};
-use crate::store::fs_io::read_slice;
+use crate::error::NodeError;
+use crate::{ARGS, StorageNodeId, StorageNodeRequest, StorageRaftTypeConfig};
+use crate::store::dedup::{blob_id_of_digest, content_digest, is_blob_id, stored_id};
+use crate::store::digest::{digest_of_id, is_corrupted, slice_digest, verify_slice, verify_slice_file};
+use crate::store::erasure::{encode, parse_shard_id, push_shards, read_erasure_coded};
+use crate::store::health::HealthState;
+use crate::store::ApplyOutcome;
+use crate::store::transfer::replicate_blob;
 
 //TODO: implement consistent read
+/// Returns a slice. `Range` and `If-Range` are honored, a satisfiable range gets a 206 with `Content-Range`.
+/// Backends keeping slices in plain files get them streamed from the file. Whole reads are verified
+/// against the id before the first byte is sent; ranged reads from files are not, hashing the whole
+/// slice for every range would defeat them, the scrubber covers those. Several ranges get the whole slice.
+/// The ETag is the digest of the slice whatever the backend, so `If-Range` holds across nodes.
 #[get("/slice/{id}")]
-pub async fn get_slice(_app: web::Data<StorageNode>, req: HttpRequest) -> impl Responder {
+pub async fn get_slice(app: web::Data<StorageNode>, req: HttpRequest) -> HttpResponse {
     let id: String = req.match_info().get("id").unwrap().into();
-    if id.len() < 64 + 1 + 1 && id.is_ascii() {
+    if digest_of_id(&id).is_none() {
         return HttpResponse::NotAcceptable().body("ID should be 64 bytes long ascii and '.' and object name.");
     }
-    match read_slice(&id) {
-        Ok(result) => HttpResponse::Ok().insert_header(("Content-Type", "application/octet-stream")).body(result),
-        Err(err) => HttpResponse::NotFound().body(format!("No such result.\nDetail: {}", err))
+
+    let meta = match app.store.state_machine.read().await.slice(&id) {
+        Ok(meta) => meta,
+        Err(err) => return NodeError::Io(err).error_response(),
+    };
+    let referenced = meta.is_some();
+    let erasure = meta.and_then(|meta| meta.erasure.map(|layout| (layout, meta.size)));
+    if let Some((layout, size)) = erasure {
+        let body = match read_erasure_coded(&app.store, &layout, size).await {
+            Ok(body) => body,
+            Err(err) => return read_error_response(err),
+        };
+        return match verify_slice(&id, &body) {
+            Ok(()) => body_response(&req, &id, body),
+            Err(err) => read_error_response(err),
+        };
+    }
+
+    // Only live slices are read through the blob they share, a deleted one must not be served from it.
+    let stored = if referenced { stored_id(&*app.backend, &id) } else { id.clone() };
+    let path = match app.backend.local_path(&stored) {
+        Some(path) => path,
+        None => {
+            let backend = app.backend.clone();
+            return match web::block(move || backend.get_verified(&stored)).await {
+                Ok(Ok(body)) => body_response(&req, &id, body),
+                Ok(Err(err)) => read_error_response(err),
+                Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+            };
+        }
+    };
+
+    let etag = slice_etag(&id);
+    if req.headers().contains_key(header::RANGE) && (!if_range_matches(&req, &etag) || has_several_ranges(&req)) {
+        // A stale `If-Range` or several ranges get the whole slice, which the file server would not give:
+        // it serves the first range only. Read it here.
+        let backend = app.backend.clone();
+        return match web::block(move || backend.get_verified(&stored)).await {
+            Ok(Ok(body)) => body_response(&req, &id, body),
+            Ok(Err(err)) => read_error_response(err),
+            Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+        };
+    }
+    if !req.headers().contains_key(header::RANGE) {
+        let verify_id = id.clone();
+        let verify_path = path.clone();
+        match web::block(move || verify_slice_file(&verify_id, &verify_path)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return read_error_response(err),
+            Err(err) => return HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+        }
+    }
+
+    match web::block(move || NamedFile::open(path)).await {
+        Ok(Ok(file)) => {
+            // Its own validators come from the inode and the modification time, they differ from node to node.
+            let mut response = file
+                .set_content_type(mime::APPLICATION_OCTET_STREAM)
+                .disable_content_disposition()
+                .use_etag(false)
+                .use_last_modified(false)
+                .into_response(&req);
+            if let Ok(etag) = header::HeaderValue::from_str(&etag) {
+                response.headers_mut().insert(header::ETAG, etag);
+            }
+            response
+        }
+        Ok(Err(err)) => HttpResponse::NotFound().body(format!("No such result.\nDetail: {}", err)),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    }
+}
+
+fn read_error_response(err: io::Error) -> HttpResponse {
+    if is_corrupted(&err) {
+        tracing::error!("refusing to serve corrupted slice: {}", err);
+        HttpResponse::InternalServerError().body(format!("Slice is corrupted.\nDetail: {}", err))
+    } else {
+        HttpResponse::NotFound().body(format!("No such result.\nDetail: {}", err))
     }
 }
 
+/// ETag of slice `id`: the digest it carries.
+fn slice_etag(id: &str) -> String {
+    format!("\"{}\"", digest_of_id(id).unwrap_or_default())
+}
+
+/// Tells whether the `Range` of `req` applies: there is no `If-Range`, or it is `etag`.
+fn if_range_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+       .get(header::IF_RANGE)
+       .map_or(true, |value| value.to_str().map_or(false, |value| value == etag))
+}
+
+/// Tells whether the `Range` of `req` asks for several ranges, which are answered with the whole slice.
+fn has_several_ranges(req: &HttpRequest) -> bool {
+    req.headers()
+       .get(header::RANGE)
+       .and_then(|value| value.to_str().ok())
+       .map_or(false, |value| value.contains(','))
+}
+
+/// Serves a slice held in memory, honoring a single `Range`. The digest in the id is the ETag.
+fn body_response(req: &HttpRequest, id: &str, body: Vec<u8>) -> HttpResponse {
+    let etag = slice_etag(id);
+    let range = req.headers()
+                   .get(header::RANGE)
+                   .filter(|_| if_range_matches(req, &etag))
+                   .and_then(|value| value.to_str().ok());
+
+    if let Some(range) = range {
+        let size = body.len() as u64;
+        match HttpRange::parse(range, size) {
+            Ok(ranges) if ranges.len() == 1 => {
+                let (start, length) = (ranges[0].start, ranges[0].length);
+                return HttpResponse::PartialContent()
+                    .insert_header((header::CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM))
+                    .insert_header((header::ETAG, etag))
+                    .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, start + length - 1, size)))
+                    .body(body[start as usize..(start + length) as usize].to_vec());
+            }
+            Err(_) => {
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                    .finish();
+            }
+            // Multipart ranges are not supported, the whole slice is returned.
+            Ok(_) => {}
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM))
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .body(body)
+}
+
+/// Returns metadata of a slice in headers, the slice content is not read.
+#[head("/slice/{id}")]
+pub async fn head_slice(app: web::Data<StorageNode>, req: HttpRequest) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    let digest = match digest_of_id(&id) {
+        Some(digest) => digest.to_string(),
+        None => return HttpResponse::NotAcceptable().finish(),
+    };
+
+    let meta = match app.store.state_machine.read().await.slice(&id) {
+        Ok(meta) => meta,
+        Err(err) => return NodeError::Io(err).error_response(),
+    };
+    let layout = meta.as_ref().and_then(|meta| meta.erasure.as_ref());
+
+    // Erasure coded slices are described by their layout, their shards are spread over the group.
+    let stored = match (&meta, layout) {
+        (Some(meta), Some(layout)) => Ok((meta.size, layout.shard_size * layout.shards.len() as u64)),
+        (Some(_), None) => app.backend.stat(&stored_id(&*app.backend, &id)).map(|stat| (stat.size, stat.stored_size)),
+        (None, _) => app.backend.stat(&id).map(|stat| (stat.size, stat.stored_size)),
+    };
+    let (size, stored_size) = match stored {
+        Ok(sizes) => sizes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("failed to stat slice {}: {}", id, err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-Slice-Size", size))
+        .insert_header(("X-Slice-Stored-Size", stored_size))
+        .insert_header(("X-Slice-Digest", digest))
+        .insert_header(("X-Slice-Storage-Class", if layout.is_some() { "erasure" } else { "replicated" }));
+    if let Some(layout) = layout {
+        response.insert_header(("X-Slice-Erasure-Coding", format!("{}+{}", layout.data_shards, layout.parity_shards)));
+    }
+    // Slices written before the state machine kept metadata only have size and digest.
+    if let Some(meta) = meta {
+        response
+            .insert_header(("X-Slice-Log-Index", meta.log_index))
+            .insert_header(("X-Slice-Written-At", meta.written_at));
+    }
+    response.finish()
+}
+
 #[put("/slice/{id}")]
 pub async fn put_slice(app: web::Data<StorageNode>, req: HttpRequest, body: web::Bytes) -> HttpResponse {
     let id: String = req.match_info().get("id").unwrap().into();
-    println!("put: {}", id);
-    if id.len() < 64 + 1 + 1 && id.is_ascii() {
+    tracing::debug!("put: {}", id);
+    let expected_digest = match digest_of_id(&id) {
+        Some(digest) => digest,
+        None => return HttpResponse::NotAcceptable().body("ID should be 64 bytes long ascii and '.' and object name."),
+    };
+    let actual_digest = slice_digest(&body);
+    if !expected_digest.eq_ignore_ascii_case(&actual_digest) {
+        return HttpResponse::BadRequest().body(format!("Body digest {} does not match ID.", actual_digest));
+    }
+
+    if app.store.health.state() == HealthState::ReadOnly {
+        return HttpResponse::ServiceUnavailable().body("Node is read-only, its storage is failing.");
+    }
+    if !app.capacity.accepts_writes() {
+        return HttpResponse::InsufficientStorage().body("Storage is above the high watermark.");
+    }
+
+    let written_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    match req.headers().get("X-Storage-Class").map(|value| value.to_str().unwrap_or_default()) {
+        None | Some("replicated") => {}
+        Some("erasure") => {
+            let (data_shards, parity_shards) = match erasure_coding(&req) {
+                Some(coding) => coding,
+                None => return HttpResponse::BadRequest().body("X-Erasure-Coding should be <data shards>+<parity shards>."),
+            };
+            return put_erasure_coded(&app, id, body, data_shards, parity_shards, written_at).await;
+        }
+        Some(class) => return HttpResponse::BadRequest().body(format!("Unknown storage class {}.", class)),
+    }
+
+    put_replicated(&app, id, body, written_at).await
+}
+
+/// Redirects to the leader when this node is not it. Writes that place content before they are
+/// proposed go through the leader, it decides on the placement with the membership it sees.
+async fn redirect_to_leader(app: &StorageNode, id: &str) -> Option<HttpResponse> {
+    let leader = app.raft.metrics().borrow().current_leader;
+    if leader == Some(app.id) {
+        return None;
+    }
+    let leader_addr = {
+        let state_machine = app.store.state_machine.read().await;
+        let membership = &state_machine.last_membership.membership;
+        leader.and_then(|leader| membership.get_node(&leader)).map(|node| node.addr.clone())
+    };
+    Some(match leader_addr {
+        Some(addr) => HttpResponse::TemporaryRedirect()
+            .insert_header((header::LOCATION, format!("http://{}/slice/{}", addr, id)))
+            .finish(),
+        None => HttpResponse::ServiceUnavailable().body("No leader to place the slice."),
+    })
+}
+
+/// Stores the slice content on a majority of the voters and then commits its id, digest and size through raft.
+/// The content stays out of the raft log, the replicas that did not get it fetch it when they apply the entry.
+async fn put_replicated(app: &StorageNode, id: String, body: web::Bytes, written_at: u64) -> HttpResponse {
+    if let Some(redirect) = redirect_to_leader(app, &id).await {
+        return redirect;
+    }
+
+    let digest = content_digest(&id).unwrap_or_default();
+    let size = body.len() as u64;
+    // Content already stored is left behind if this fails, the scrubbers collect it once nothing referred to it for a while.
+    if let Err(err) = replicate_blob(&app.store, &blob_id_of_digest(&digest), body.to_vec()).await {
+        tracing::error!("failed to replicate content of slice {}: {}", id, err);
+        return HttpResponse::ServiceUnavailable().body(format!("Detail: {}", err));
+    }
+
+    let request = ClientWriteRequest::new(EntryPayload::Normal(StorageNodeRequest::StoreReference { id: id.clone(), digest, size, written_at }));
+    let response = app.raft.client_write(request).await;
+    client_write_response(response, &format!("slice/{}", id))
+}
+
+/// Shard counts from the `X-Erasure-Coding` header, e.g. "4+2", or the configured defaults.
+fn erasure_coding(req: &HttpRequest) -> Option<(usize, usize)> {
+    let value = match req.headers().get("X-Erasure-Coding") {
+        Some(value) => value.to_str().ok()?,
+        None => return Some((ARGS.erasure_data_shards, ARGS.erasure_parity_shards)),
+    };
+    let (data_shards, parity_shards) = value.split_once('+')?;
+    Some((data_shards.trim().parse().ok()?, parity_shards.trim().parse().ok()?))
+}
+
+/// Encodes the slice into shards, stores them on their nodes and then commits the layout through raft.
+/// Placement follows the membership seen by the leader, so followers redirect the writer to it.
+async fn put_erasure_coded(
+    app: &StorageNode,
+    id: String,
+    body: web::Bytes,
+    data_shards: usize,
+    parity_shards: usize,
+    written_at: u64,
+) -> HttpResponse {
+    if let Some(redirect) = redirect_to_leader(app, &id).await {
+        return redirect;
+    }
+    let members: Vec<StorageNodeId> = {
+        let state_machine = app.store.state_machine.read().await;
+        state_machine.last_membership.membership.all_members().iter().cloned().collect()
+    };
+
+    let size = body.len() as u64;
+    let encode_id = id.clone();
+    let (layout, shards) = match web::block(move || encode(&encode_id, &body, data_shards, parity_shards, &members)).await {
+        Ok(Ok(encoded)) => encoded,
+        Ok(Err(err)) => return HttpResponse::BadRequest().body(format!("Detail: {}", err)),
+        Err(err) => return HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    };
+    // Shards already stored are left behind if this fails, the scrubbers collect them once nothing referred to them for a while.
+    if let Err(err) = push_shards(&app.store, &layout, shards).await {
+        tracing::error!("failed to place shards of slice {}: {}", id, err);
+        return HttpResponse::ServiceUnavailable().body(format!("Detail: {}", err));
+    }
+
+    let request = ClientWriteRequest::new(EntryPayload::Normal(StorageNodeRequest::StoreErasureCoded { id: id.clone(), size, layout, written_at }));
+    let response = app.raft.client_write(request).await;
+    client_write_response(response, &format!("slice/{}", id))
+}
+
+/// Returns a shard held by this node, peers read shards through it to rebuild erasure coded slices.
+#[get("/shard/{id}")]
+pub async fn get_shard(app: web::Data<StorageNode>, req: HttpRequest) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    if parse_shard_id(&id).is_none() {
+        return HttpResponse::NotAcceptable().body("Not a shard ID.");
+    }
+
+    let backend = app.backend.clone();
+    let read_id = id.clone();
+    match web::block(move || backend.get_verified(&read_id)).await {
+        Ok(Ok(body)) => body_response(&req, &id, body),
+        Ok(Err(err)) => read_error_response(err),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    }
+}
+
+/// Stores a shard on this node, sent by the leader placing an erasure coded slice.
+/// Not replicated: the layout committed afterwards says which node holds which shard.
+#[put("/shard/{id}")]
+pub async fn put_shard(app: web::Data<StorageNode>, req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    if parse_shard_id(&id).is_none() {
+        return HttpResponse::NotAcceptable().body("Not a shard ID.");
+    }
+    if app.store.health.state() == HealthState::ReadOnly {
+        return HttpResponse::ServiceUnavailable().body("Node is read-only, its storage is failing.");
+    }
+    if !app.capacity.accepts_writes() {
+        return HttpResponse::InsufficientStorage().body("Storage is above the high watermark.");
+    }
+
+    // It may be garbage already, left by an earlier write of the same content.
+    app.store.collector.keep(&id);
+    let backend = app.backend.clone();
+    let store_id = id.clone();
+    match web::block(move || backend.put_verified(&store_id, &body)).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(err)) if is_corrupted(&err) => HttpResponse::BadRequest().body(format!("Detail: {}", err)),
+        Ok(Err(err)) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    }
+}
+
+/// Returns a blob held by this node, replicas that were not sent the content of a slice fetch it here,
+/// as do nodes installing a snapshot.
+#[get("/blob/{id}")]
+pub async fn get_blob(app: web::Data<StorageNode>, req: HttpRequest) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    if !is_blob_id(&id) {
+        return HttpResponse::NotAcceptable().body("Not a blob ID.");
+    }
+
+    // Slices written before deduplication hold their content under their own id.
+    let mut read_ids = vec![id.clone()];
+    if let Some(digest) = content_digest(&id) {
+        let state_machine = app.store.state_machine.read().await;
+        if let Ok((slices, _)) = state_machine.list(&format!("{}.", digest), None, 1) {
+            read_ids.extend(slices.into_iter().filter(|(_, meta)| meta.erasure.is_none()).map(|(id, _)| id));
+        }
+    }
+    let backend = app.backend.clone();
+    let read = move || {
+        let mut result = Err(io::Error::new(io::ErrorKind::NotFound, "no such blob"));
+        for id in &read_ids {
+            result = backend.get_verified(id);
+            if !matches!(&result, Err(err) if err.kind() == io::ErrorKind::NotFound) {
+                break;
+            }
+        }
+        result
+    };
+    match web::block(read).await {
+        Ok(Ok(body)) => body_response(&req, &id, body),
+        Ok(Err(err)) => read_error_response(err),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    }
+}
+
+/// Stores a blob on this node, sent by the leader before it proposes the slice referring to it.
+/// Not replicated: the entry committed afterwards makes it part of the state machine.
+#[put("/blob/{id}")]
+pub async fn put_blob(app: web::Data<StorageNode>, req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    if !is_blob_id(&id) {
+        return HttpResponse::NotAcceptable().body("Not a blob ID.");
+    }
+    if app.store.health.state() == HealthState::ReadOnly {
+        return HttpResponse::ServiceUnavailable().body("Node is read-only, its storage is failing.");
+    }
+    if !app.capacity.accepts_writes() {
+        return HttpResponse::InsufficientStorage().body("Storage is above the high watermark.");
+    }
+
+    // It may be garbage already, left by an earlier write of the same content.
+    app.store.collector.keep(&id);
+    let backend = app.backend.clone();
+    let store_id = id.clone();
+    match web::block(move || backend.put_verified(&store_id, &body)).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(err)) if is_corrupted(&err) => HttpResponse::BadRequest().body(format!("Detail: {}", err)),
+        Ok(Err(err)) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Detail: {}", err)),
+    }
+}
+
+#[delete("/slice/{id}")]
+pub async fn delete_slice(app: web::Data<StorageNode>, req: HttpRequest) -> HttpResponse {
+    let id: String = req.match_info().get("id").unwrap().into();
+    if digest_of_id(&id).is_none() {
         return HttpResponse::NotAcceptable().body("ID should be 64 bytes long ascii and '.' and object name.");
     }
 
-    let request = ClientWriteRequest::new(EntryPayload::Normal(StorageNodeRequest::StoreData { id: id.clone(), value: body.to_vec() }));
+    let request = ClientWriteRequest::new(EntryPayload::Normal(StorageNodeRequest::DeleteData { ids: vec![id.clone()] }));
     let response = app.raft.client_write(request).await;
+    client_write_response(response, &format!("slice/{}", id))
+}
+
+/// Deletes a batch of slices with a single raft log entry. The body is a json array of ids.
+#[post("/slices/delete")]
+pub async fn delete_slices(app: web::Data<StorageNode>, ids: Json<Vec<String>>) -> HttpResponse {
+    let ids = ids.into_inner();
+    if ids.is_empty() || ids.iter().any(|id| digest_of_id(id).is_none()) {
+        return HttpResponse::NotAcceptable().body("Every ID should be 64 bytes long ascii and '.' and object name.");
+    }
+
+    let request = ClientWriteRequest::new(EntryPayload::Normal(StorageNodeRequest::DeleteData { ids }));
+    let response = app.raft.client_write(request).await;
+    client_write_response(response, "slices/delete")
+}
+
+/// Slices listed by `/slices` when the request gives no limit, and at most.
+const DEFAULT_LIST_LIMIT: usize = 1000;
+const MAX_LIST_LIMIT: usize = 10000;
+
+#[derive(Deserialize, Debug)]
+pub struct ListSlicesQuery {
+    #[serde(default)]
+    prefix: String,
+    start_after: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Lists the slices of the state machine in id order, a page at a time.
+/// The next page starts after the `next_start_after` of this one, which stays valid whatever is written meanwhile.
+#[get("/slices")]
+pub async fn list_slices(app: web::Data<StorageNode>, query: web::Query<ListSlicesQuery>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        return HttpResponse::BadRequest().body(format!("limit should be between 1 and {}.", MAX_LIST_LIMIT));
+    }
+
+    let state_machine = app.store.state_machine.read().await;
+    let (slices, truncated) = match state_machine.list(&query.prefix, query.start_after.as_deref(), limit) {
+        Ok(page) => page,
+        Err(err) => return NodeError::Io(err).error_response(),
+    };
+    let next_start_after = slices.last().filter(|_| truncated).map(|(id, _)| id.clone());
+    let slices: Vec<_> = slices
+        .into_iter()
+        .map(|(id, meta)| json!({
+            "id": id,
+            "size": meta.size,
+            "log_index": meta.log_index,
+            "written_at": meta.written_at,
+            "storage_class": if meta.erasure.is_some() { "erasure" } else { "replicated" },
+        }))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "slices": slices,
+        "is_truncated": truncated,
+        "next_start_after": next_start_after,
+    }))
+}
+
+/// Maps the outcome of a raft client write into a http response.
+/// Followers answer with a redirect to `path` on the leader.
+fn client_write_response(
+    response: Result<ClientWriteResponse<StorageRaftTypeConfig>, ClientWriteError<StorageNodeId>>,
+    path: &str,
+) -> HttpResponse {
     match &response {
         Err(e) => {
             match e {
                 ClientWriteError::ForwardToLeader(nid) => {
                     if let Some(leader) = nid.clone().leader_node {
                         HttpResponse::TemporaryRedirect()
-                            .insert_header((header::LOCATION,format!("http://{}/slice/{}", leader.addr, id)))
+                            .insert_header((header::LOCATION,format!("http://{}/{}", leader.addr, path)))
                             .json(&response)
                     } else {
                         HttpResponse::InternalServerError()
                             .json(&response)
                     }
                 }
+                ClientWriteError::Fatal(fatal) => NodeError::from(fatal.clone()).error_response(),
                 _ => {
                     HttpResponse::InternalServerError()
                         .json(&response)
                 }
             }
         }
-        Ok(..) => HttpResponse::Ok()
-            .json(&response)
+        // Rejected entries are committed too, the client gets why nothing was written.
+        Ok(written) => match written.data.outcome {
+            ApplyOutcome::Applied { .. } => HttpResponse::Ok().json(&response),
+            ApplyOutcome::Rejected { .. } => HttpResponse::UnprocessableEntity().json(&response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn several_ranges_get_the_whole_slice() {
+        let id = format!("{}.slice", slice_digest(b"0123456789"));
+        let req = TestRequest::default().insert_header((header::RANGE, "bytes=0-1,5-6")).to_http_request();
+        assert!(has_several_ranges(&req));
+        assert_eq!(body_response(&req, &id, b"0123456789".to_vec()).status(), StatusCode::OK);
+
+        let req = TestRequest::default().insert_header((header::RANGE, "bytes=2-4")).to_http_request();
+        assert!(!has_several_ranges(&req));
+        assert_eq!(body_response(&req, &id, b"0123456789".to_vec()).status(), StatusCode::PARTIAL_CONTENT);
     }
 }
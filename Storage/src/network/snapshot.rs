@@ -0,0 +1,191 @@
+use std::io;
+
+use openraft::raft::{InstallSnapshotRequest, InstallSnapshotResponse};
+use openraft::{Config, SnapshotMeta, Vote};
+use serde::{Deserialize, Serialize};
+
+use crate::{StorageNodeId, StorageRaftTypeConfig};
+use crate::store::codec;
+use crate::store::digest::slice_digest;
+
+/// Room left in a request for the header of a chunk, it carries the membership.
+const CHUNK_HEADER_ROOM: usize = 1 << 20;
+
+/// An `InstallSnapshotRequest` but its data, which follows it in the body of the request.
+#[derive(Serialize, Deserialize, Debug)]
+struct ChunkHeader {
+    vote: Vote<StorageNodeId>,
+    meta: SnapshotMeta<StorageNodeId>,
+    offset: u64,
+    done: bool,
+    /// SHA-256 of the chunk, hex.
+    digest: String,
+}
+
+/// Largest body of a `/raft-snapshot` request.
+pub fn max_request_size(config: &Config) -> usize {
+    config.snapshot_max_chunk_size as usize + CHUNK_HEADER_ROOM
+}
+
+/// Body of a `/raft-snapshot` request: the length of the header (u32, big endian), the header encoded by `codec`,
+/// then the chunk as it is.
+pub fn encode_chunk(req: &InstallSnapshotRequest<StorageRaftTypeConfig>) -> io::Result<Vec<u8>> {
+    let header = codec::encode(&ChunkHeader {
+        vote: req.vote,
+        meta: req.meta.clone(),
+        offset: req.offset,
+        done: req.done,
+        digest: slice_digest(&req.data),
+    })?;
+
+    let mut body = Vec::with_capacity(4 + header.len() + req.data.len());
+    body.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    body.extend_from_slice(&header);
+    body.extend_from_slice(&req.data);
+    Ok(body)
+}
+
+/// Reads a chunk encoded by `encode_chunk`, checking it against its digest.
+pub fn decode_chunk(body: &[u8]) -> io::Result<InstallSnapshotRequest<StorageRaftTypeConfig>> {
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated snapshot chunk");
+    if body.len() < 4 {
+        return Err(truncated());
+    }
+    let (length, rest) = body.split_at(4);
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    if rest.len() < length {
+        return Err(truncated());
+    }
+    let (header, data) = rest.split_at(length);
+    let header: ChunkHeader = codec::decode(header)?;
+
+    let digest = slice_digest(data);
+    if !header.digest.eq_ignore_ascii_case(&digest) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk at offset {} of snapshot {} has digest {}, expected {}", header.offset, header.meta.snapshot_id, digest, header.digest),
+        ));
+    }
+    Ok(InstallSnapshotRequest {
+        vote: header.vote,
+        meta: header.meta,
+        offset: header.offset,
+        data: data.to_vec(),
+        done: header.done,
+    })
+}
+
+/// How far the snapshot being sent to a node got. When raft starts a snapshot over after a network
+/// failure, the chunks the node already has are not sent again, its raft keeps receiving where it was.
+#[derive(Debug)]
+pub struct SnapshotProgress {
+    snapshot_id: String,
+    acked: u64,
+    response: InstallSnapshotResponse<StorageNodeId>,
+}
+
+impl SnapshotProgress {
+    /// Progress after `req` was acknowledged with `response`, None once the snapshot is complete.
+    pub fn after(req: &InstallSnapshotRequest<StorageRaftTypeConfig>, response: &InstallSnapshotResponse<StorageNodeId>) -> Option<Self> {
+        if req.done {
+            return None;
+        }
+        Some(SnapshotProgress {
+            snapshot_id: req.meta.snapshot_id.clone(),
+            acked: req.offset + req.data.len() as u64,
+            response: response.clone(),
+        })
+    }
+
+    /// The response to `req` if the node acknowledged its chunk already.
+    pub fn acknowledged(&self, req: &InstallSnapshotRequest<StorageRaftTypeConfig>) -> Option<InstallSnapshotResponse<StorageNodeId>> {
+        let covered = req.meta.snapshot_id == self.snapshot_id && req.offset + req.data.len() as u64 <= self.acked;
+        if covered && !req.done {
+            Some(self.response.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openraft::{EffectiveMembership, LeaderId, LogId};
+
+    use super::*;
+
+    fn chunk(snapshot_id: &str, offset: u64, data: &[u8], done: bool) -> InstallSnapshotRequest<StorageRaftTypeConfig> {
+        InstallSnapshotRequest {
+            vote: Vote::new(2, 1),
+            meta: SnapshotMeta {
+                last_log_id: LogId::new(LeaderId::new(2, 1), 10),
+                last_membership: EffectiveMembership::default(),
+                snapshot_id: snapshot_id.to_string(),
+            },
+            offset,
+            data: data.to_vec(),
+            done,
+        }
+    }
+
+    fn response() -> InstallSnapshotResponse<StorageNodeId> {
+        InstallSnapshotResponse { vote: Vote::new(2, 1) }
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let req = chunk("s1", 4096, b"chunk of a snapshot", true);
+        let decoded = decode_chunk(&encode_chunk(&req).unwrap()).unwrap();
+        assert_eq!(decoded.vote, req.vote);
+        assert_eq!(decoded.meta, req.meta);
+        assert_eq!(decoded.offset, 4096);
+        assert_eq!(decoded.data, req.data);
+        assert!(decoded.done);
+    }
+
+    #[test]
+    fn corrupted_chunk_is_refused() {
+        let body = encode_chunk(&chunk("s1", 0, b"chunk of a snapshot", false)).unwrap();
+
+        let mut corrupted = body.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(decode_chunk(&corrupted).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(decode_chunk(&body[..body.len() - 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode_chunk(&body[..2]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut long_header = body;
+        long_header[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(decode_chunk(&long_header).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn only_acknowledged_chunks_are_skipped() {
+        let progress = SnapshotProgress::after(&chunk("s1", 0, &[0; 100], false), &response()).unwrap();
+
+        assert!(progress.acknowledged(&chunk("s1", 0, &[0; 100], false)).is_some());
+        assert!(progress.acknowledged(&chunk("s1", 50, &[0; 50], false)).is_some());
+        // Past what the node acknowledged, or out of order.
+        assert!(progress.acknowledged(&chunk("s1", 100, &[0; 100], false)).is_none());
+        assert!(progress.acknowledged(&chunk("s1", 50, &[0; 100], false)).is_none());
+        // Another snapshot, or the last chunk, which makes the node install it.
+        assert!(progress.acknowledged(&chunk("s2", 0, &[0; 100], false)).is_none());
+        assert!(progress.acknowledged(&chunk("s1", 0, &[0; 100], true)).is_none());
+    }
+
+    #[test]
+    fn resumed_transfer_sends_the_rest() {
+        let chunks = [chunk("s1", 0, &[1; 10], false), chunk("s1", 10, &[2; 10], false), chunk("s1", 20, &[3; 5], true)];
+
+        // The transfer broke after the second chunk was acknowledged.
+        let mut progress = None;
+        for req in &chunks[..2] {
+            progress = SnapshotProgress::after(req, &response());
+        }
+        let progress = progress.unwrap();
+
+        // Raft sends the snapshot again from the start.
+        let sent: Vec<u64> = chunks.iter().filter(|req| progress.acknowledged(req).is_none()).map(|req| req.offset).collect();
+        assert_eq!(sent, vec![20]);
+        assert!(SnapshotProgress::after(&chunks[2], &response()).is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use std::convert::TryInto;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::ArgEnum;
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+
+/// Compressed slices start with this magic, a format version, the codec and the original length.
+const MAGIC: &[u8; 6] = b"HADSSC";
+const FORMAT_VERSION: u8 = 1;
+/// Also the most compression adds to a slice, raw slices starting with the magic get a header.
+pub const HEADER_LENGTH: usize = 16;
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionCodec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl CompressionCodec {
+    fn tag(self) -> u8 {
+        match self {
+            CompressionCodec::None => 0,
+            CompressionCodec::Zstd => 1,
+            CompressionCodec::Lz4 => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(CompressionCodec::None),
+            1 => Ok(CompressionCodec::Zstd),
+            2 => Ok(CompressionCodec::Lz4),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression codec {}", tag))),
+        }
+    }
+}
+
+struct Header {
+    codec: CompressionCodec,
+    original_length: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[0..6].copy_from_slice(MAGIC);
+        bytes[6] = FORMAT_VERSION;
+        bytes[7] = self.codec.tag();
+        bytes[8..16].copy_from_slice(&self.original_length.to_be_bytes());
+        bytes
+    }
+
+    /// Returns None when `bytes` do not start with a header, i.e. the slice is stored raw.
+    fn parse(bytes: &[u8]) -> io::Result<Option<Header>> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..6] != MAGIC {
+            return Ok(None);
+        }
+        if bytes[6] != FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression format {}", bytes[6])));
+        }
+        Ok(Some(Header {
+            codec: CompressionCodec::from_tag(bytes[7])?,
+            original_length: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }))
+    }
+}
+
+/// Length of the header `stored` starts with, 0 for a raw slice. Left in clear when the slice is sealed.
+pub fn header_length(stored: &[u8]) -> usize {
+    match Header::parse(stored) {
+        Ok(Some(_)) => HEADER_LENGTH,
+        _ => 0,
+    }
+}
+
+/// Compresses slices on their way to the `inner` backend and decompresses them on the way back.
+///
+/// A slice is stored compressed behind a 16 bytes header only when that makes it smaller, otherwise
+/// it is stored raw, so that its file can still be served directly. Raw slices that happen to start
+/// with the header magic get a header with codec `None`. Slices written before compression was
+/// enabled have no header and are read as they are, so the codec can be changed at any time.
+#[derive(Debug)]
+pub struct CompressingBackend {
+    inner: Arc<dyn SliceBackend>,
+    codec: CompressionCodec,
+    level: i32,
+}
+
+impl CompressingBackend {
+    pub fn new(inner: Arc<dyn SliceBackend>, codec: CompressionCodec, level: i32) -> Self {
+        CompressingBackend { inner, codec, level }
+    }
+
+    /// The header and payload to store `body` compressed, None if that does not make it smaller.
+    fn compress(&self, body: &[u8]) -> io::Result<Option<(Header, Vec<u8>)>> {
+        let compressed = match self.codec {
+            CompressionCodec::None => return Ok(None),
+            CompressionCodec::Zstd => zstd::bulk::compress(body, self.level)?,
+            CompressionCodec::Lz4 => lz4_flex::compress(body),
+        };
+        if compressed.len() + HEADER_LENGTH >= body.len() {
+            return Ok(None);
+        }
+        Ok(Some((Header { codec: self.codec, original_length: body.len() as u64 }, compressed)))
+    }
+}
+
+fn decompress(header: &Header, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let length = header.original_length as usize;
+    let body = match header.codec {
+        CompressionCodec::None => payload.to_vec(),
+        CompressionCodec::Zstd => zstd::bulk::decompress(payload, length)?,
+        CompressionCodec::Lz4 => lz4_flex::decompress(payload, length)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+    };
+    if body.len() != length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("slice decompressed to {} bytes, {} expected", body.len(), length),
+        ));
+    }
+    Ok(body)
+}
+
+impl SliceBackend for CompressingBackend {
+    /// The header goes apart, so that `stat` reads it without reading (or opening) the whole slice.
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        if let Some((header, compressed)) = self.compress(body)? {
+            return self.inner.put_with_header(id, &header.encode(), &compressed);
+        }
+        if body.starts_with(MAGIC) {
+            let header = Header { codec: CompressionCodec::None, original_length: body.len() as u64 };
+            return self.inner.put_with_header(id, &header.encode(), body);
+        }
+        self.inner.put(id, body)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        let stored = self.inner.get(id)?;
+        match Header::parse(&stored)? {
+            Some(header) => decompress(&header, &stored[HEADER_LENGTH..]),
+            None => Ok(stored),
+        }
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        let stat = self.inner.stat(id)?;
+        match Header::parse(&self.inner.read_prefix(id, HEADER_LENGTH)?)? {
+            Some(header) => Ok(SliceStat { size: header.original_length, stored_size: stat.stored_size }),
+            None => Ok(stat),
+        }
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        self.inner.list()
+    }
+
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        self.inner.quarantine(id)
+    }
+
+    /// Only raw slices can be served straight from their file.
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        let path = self.inner.local_path(id)?;
+        match Header::parse(&self.inner.read_prefix(id, HEADER_LENGTH).ok()?) {
+            Ok(None) => Some(path),
+            _ => None,
+        }
+    }
+
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        let mut body = self.get(id)?;
+        body.truncate(length);
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::backend::MemoryBackend;
+
+    fn compressing(codec: CompressionCodec) -> (Arc<MemoryBackend>, CompressingBackend) {
+        let inner = Arc::new(MemoryBackend::default());
+        (inner.clone(), CompressingBackend::new(inner, codec, 3))
+    }
+
+    fn compressible() -> Vec<u8> {
+        b"slice content ".iter().cycle().take(4096).cloned().collect()
+    }
+
+    #[test]
+    fn header_round_trips() {
+        for codec in [CompressionCodec::None, CompressionCodec::Zstd, CompressionCodec::Lz4] {
+            let parsed = Header::parse(&Header { codec, original_length: 1 << 33 }.encode()).unwrap().unwrap();
+            assert_eq!(parsed.codec, codec);
+            assert_eq!(parsed.original_length, 1 << 33);
+        }
+    }
+
+    #[test]
+    fn truncated_header_is_raw() {
+        let header = Header { codec: CompressionCodec::Zstd, original_length: 10 }.encode();
+        for length in 0..HEADER_LENGTH {
+            assert!(Header::parse(&header[..length]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn unknown_format_or_codec_is_refused() {
+        let mut header = Header { codec: CompressionCodec::Lz4, original_length: 10 }.encode();
+        header[6] = FORMAT_VERSION + 1;
+        assert_eq!(Header::parse(&header).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut header = Header { codec: CompressionCodec::Lz4, original_length: 10 }.encode();
+        header[7] = 9;
+        assert_eq!(Header::parse(&header).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compressed_slices_round_trip() {
+        for codec in [CompressionCodec::Zstd, CompressionCodec::Lz4] {
+            let (inner, backend) = compressing(codec);
+            let body = compressible();
+            backend.put("id", &body).unwrap();
+
+            let stored = inner.get("id").unwrap();
+            assert!(stored.starts_with(MAGIC));
+            assert!(stored.len() < body.len());
+            assert_eq!(backend.get("id").unwrap(), body);
+            assert_eq!(backend.read_prefix("id", 10).unwrap(), body[..10]);
+            let stat = backend.stat("id").unwrap();
+            assert_eq!(stat.size, body.len() as u64);
+            assert_eq!(stat.stored_size, stored.len() as u64);
+        }
+    }
+
+    #[test]
+    fn incompressible_and_uncompressed_slices_are_stored_raw() {
+        let body: Vec<u8> = (0..64).map(|_| rand::random::<u8>()).collect();
+        let (inner, backend) = compressing(CompressionCodec::Zstd);
+        backend.put("random", &body).unwrap();
+        assert_eq!(inner.get("random").unwrap(), body);
+        assert_eq!(backend.get("random").unwrap(), body);
+
+        // Written before compression was enabled, or with it off.
+        let (inner, backend) = compressing(CompressionCodec::None);
+        inner.put("legacy", &compressible()).unwrap();
+        assert_eq!(backend.get("legacy").unwrap(), compressible());
+        backend.put("plain", &compressible()).unwrap();
+        assert_eq!(inner.get("plain").unwrap(), compressible());
+    }
+
+    #[test]
+    fn raw_slice_starting_with_the_magic_round_trips() {
+        let mut body = MAGIC.to_vec();
+        body.extend_from_slice(&[FORMAT_VERSION, 0xff, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let (inner, backend) = compressing(CompressionCodec::None);
+        backend.put("magic", &body).unwrap();
+        assert_eq!(inner.get("magic").unwrap().len(), HEADER_LENGTH + body.len());
+        assert_eq!(backend.get("magic").unwrap(), body);
+        assert_eq!(backend.stat("magic").unwrap().size, body.len() as u64);
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        for codec in [CompressionCodec::Zstd, CompressionCodec::Lz4] {
+            let (inner, backend) = compressing(codec);
+            backend.put("id", &compressible()).unwrap();
+            let mut stored = inner.get("id").unwrap();
+            stored.truncate(stored.len() - 4);
+            inner.put("id", &stored).unwrap();
+            assert!(backend.get("id").is_err());
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::{fs, io};
+use std::fs::{File, ReadDir};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+use crate::store::digest::digest_of_id;
+
+pub type DirectoryPath = String;
+pub type Filename = String;
+
+/// Slices are staged in files named `.tmp-<random>-<filename>` next to their final path.
+/// Filenames produced by `split_id_into_directory_and_filename` never start with '.',
+/// so anything carrying this prefix is a leftover of an interrupted write.
+const TEMP_FILE_PREFIX: &str = ".tmp-";
+
+/// The sled database lives inside `storage_location`, it must not be touched by the sweeper or the scrubber.
+pub const DATABASE_DIRECTORY: &str = "database";
+
+/// Slices failing verification are moved here by the scrubber, they are kept for inspection.
+pub const QUARANTINE_DIRECTORY: &str = "quarantine";
+
+/// Segment files of the packed small slices, see `SegmentBackend`.
+pub const SEGMENT_DIRECTORY: &str = "segments";
+
+const RESERVED_DIRECTORIES: [&str; 3] = [DATABASE_DIRECTORY, QUARANTINE_DIRECTORY, SEGMENT_DIRECTORY];
+
+/// This function splits file id into tuple(DirectoryPath, Filename)
+/// e.g. split_id_into_directory_and_filename("1234567890", 3) -> ("12/34/56","7890")
+fn split_id_into_directory_and_filename(id: &str,
+                                        directory_depth: usize,
+                                        directory_name_length: usize)
+                                        -> (DirectoryPath, Filename) {
+    let chunks: Vec<&str> = id.as_bytes()
+                   .chunks(directory_name_length)
+                   .map(|x| std::str::from_utf8(x).unwrap())
+                   .collect();
+
+    let directory: String = chunks
+        .iter()
+        .take(directory_depth)
+        .cloned()
+        .intersperse("/")
+        .collect();
+
+    let filename: String = chunks
+        .iter()
+        .skip(directory_depth)
+        .cloned()
+        .intersperse("")
+        .collect();
+
+    (directory, filename)
+}
+
+/// One file per slice, in a tree of two character directories `directory_depth` levels deep under `root`.
+#[derive(Debug, Clone)]
+pub struct DirectoryBackend {
+    root: String,
+    directory_depth: usize,
+}
+
+impl DirectoryBackend {
+    /// Opens the tree under `root` and sweeps the temp files left by interrupted writes.
+    /// Must be called before the node accepts any request.
+    pub fn open(root: &str, directory_depth: usize) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        let backend = DirectoryBackend {
+            root: root.trim_end_matches('/').into(),
+            directory_depth,
+        };
+        let swept = backend.sweep_temp_files()?;
+        tracing::info!("swept {} leftover temp files under {}", swept, backend.root);
+        Ok(backend)
+    }
+
+    /// Full path of the file holding slice `id`.
+    pub fn slice_path(&self, id: &str) -> String {
+        let (directory, filename) = split_id_into_directory_and_filename(id, self.directory_depth, 2);
+        format!("{}/{}/{}", self.root, directory, filename)
+    }
+
+    /// Inverse of `slice_path`: recovers the slice id from a file path under `root`.
+    /// Returns None for temp files and anything that is not a slice.
+    fn id_of_path(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let filename = relative.file_name()?.to_str()?;
+        if filename.starts_with(TEMP_FILE_PREFIX) {
+            return None;
+        }
+        let id: String = relative.to_str()?.split('/').collect();
+        digest_of_id(&id)?;
+        Some(id)
+    }
+
+    /// Tells whether a top level directory of `root` holds something else than slices.
+    fn is_reserved_directory(&self, path: &Path) -> bool {
+        path.parent() == Some(Path::new(&self.root))
+            && path.file_name().map_or(false, |name| RESERVED_DIRECTORIES.iter().any(|reserved| name == *reserved))
+    }
+
+    /// Removes temp files left behind by writes interrupted by a crash.
+    fn sweep_temp_files(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut pending = vec![PathBuf::from(&self.root)];
+        while let Some(directory) = pending.pop() {
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    if !self.is_reserved_directory(&path) {
+                        pending.push(path);
+                    }
+                } else if entry.file_name().to_string_lossy().starts_with(TEMP_FILE_PREFIX) {
+                    tracing::warn!("removing leftover temp file {}", path.display());
+                    fs::remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl SliceBackend for DirectoryBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        let (directory, filename) = split_id_into_directory_and_filename(id, self.directory_depth, 2);
+
+        let full_directory = format!("{}/{}", self.root, directory);
+        create_dir_all_synced(Path::new(&full_directory))?;
+
+        let full_path = format!("{}/{}", full_directory, filename);
+        let temp_path = format!("{}/{}{:016x}-{}", full_directory, TEMP_FILE_PREFIX, rand::random::<u64>(), filename);
+
+        if let Err(err) = write_synced(&temp_path, body) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        if let Err(err) = fs::rename(&temp_path, &full_path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        sync_directory(&full_directory)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.slice_path(id))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        let path = self.slice_path(id);
+        match fs::remove_file(&path) {
+            Ok(()) => sync_directory(Path::new(&path).parent().unwrap()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        let size = fs::metadata(self.slice_path(id))?.len();
+        Ok(SliceStat { size, stored_size: size })
+    }
+
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        let mut prefix = Vec::with_capacity(length);
+        File::open(self.slice_path(id))?.take(length as u64).read_to_end(&mut prefix)?;
+        Ok(prefix)
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        Ok(Box::new(DirectoryWalker {
+            backend: self.clone(),
+            pending: vec![PathBuf::from(&self.root)],
+            entries: None,
+        }))
+    }
+
+    /// Moves the file out of the tree into the quarantine directory.
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        let quarantine_directory = format!("{}/{}", self.root, QUARANTINE_DIRECTORY);
+        fs::create_dir_all(&quarantine_directory)?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let quarantined_path = format!("{}/{}.{}", quarantine_directory, id, timestamp);
+        fs::rename(self.slice_path(id), &quarantined_path)?;
+        sync_directory(&quarantine_directory)?;
+        tracing::warn!("slice {} quarantined to {}", id, quarantined_path);
+        Ok(())
+    }
+
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(self.slice_path(id)))
+    }
+}
+
+/// Walks the tree lazily, one directory listing at a time.
+struct DirectoryWalker {
+    backend: DirectoryBackend,
+    pending: Vec<PathBuf>,
+    entries: Option<ReadDir>,
+}
+
+impl Iterator for DirectoryWalker {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entries = match &mut self.entries {
+                Some(entries) => entries,
+                None => {
+                    let directory = self.pending.pop()?;
+                    match fs::read_dir(directory) {
+                        Ok(entries) => self.entries.insert(entries),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+            };
+
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.entries = None;
+                    continue;
+                }
+            };
+
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if !self.backend.is_reserved_directory(&path) {
+                        self.pending.push(path);
+                    }
+                }
+                Ok(_) => {
+                    if let Some(id) = self.backend.id_of_path(&path) {
+                        return Some(Ok(id));
+                    }
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Writes `body` into a fresh file and fsyncs it before returning.
+fn write_synced(path: &str, body: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(body)?;
+    file.sync_all()
+}
+
+/// Fsyncs a directory so that a rename inside it survives a power loss.
+fn sync_directory<P: AsRef<Path>>(directory: P) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+/// `fs::create_dir_all`, fsyncing the parent of every directory it creates:
+/// a slice renamed into a new directory is only durable once the directory itself is.
+fn create_dir_all_synced(directory: &Path) -> io::Result<()> {
+    let mut missing = Vec::new();
+    let mut current = directory;
+    while !current.is_dir() {
+        missing.push(current);
+        current = match current.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    for created in missing.into_iter().rev() {
+        match fs::create_dir(created) {
+            // Created by a concurrent write, which may not have synced its parent yet.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            result => result?,
+        }
+        match created.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => sync_directory(parent)?,
+            _ => sync_directory(".")?,
+        }
+    }
+    Ok(())
+}
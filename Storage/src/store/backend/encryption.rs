@@ -0,0 +1,295 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+use crate::store::keyring::Keyring;
+
+/// Seals slices with the `keyring` on their way to the `inner` backend and opens them on the way back.
+///
+/// Slices written before encryption was enabled have no sealing header and are read as they are,
+/// `reencrypt` rewrites them, and slices sealed under an older master key, under the active key.
+/// Without a keyring new slices are stored in plaintext and sealed ones cannot be read.
+#[derive(Debug)]
+pub struct EncryptingBackend {
+    inner: Arc<dyn SliceBackend>,
+    keyring: Option<Arc<Keyring>>,
+    /// Held by `reencrypt` around each read-rewrite, so that a slice deleted meanwhile is not written back.
+    rewrite: Mutex<()>,
+}
+
+impl EncryptingBackend {
+    pub fn new(inner: Arc<dyn SliceBackend>, keyring: Option<Arc<Keyring>>) -> Self {
+        EncryptingBackend { inner, keyring, rewrite: Mutex::new(()) }
+    }
+
+    fn keyring(&self, id: &str) -> io::Result<&Keyring> {
+        self.keyring.as_deref().ok_or_else(|| io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("slice {} is encrypted but no master key file is configured", id),
+        ))
+    }
+
+    fn is_current(&self, id: &str) -> io::Result<bool> {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return Ok(true),
+        };
+        let prefix = self.inner.read_prefix(id, Keyring::header_prefix_length())?;
+        Ok(Keyring::key_id_of(&prefix) == Some(keyring.active_key_id()) && Keyring::clear_header(&prefix).is_some())
+    }
+
+    /// Rewrites every slice that is in plaintext, sealed under another key than the active one or sealed before
+    /// seals had a clear header. `clear_header` tells how many first bytes of a slice are its header, left in clear.
+    /// Runs online: slices stay readable throughout, through their old or their new form.
+    /// Returns how many slices were rewritten.
+    pub fn reencrypt(&self, clear_header: fn(&[u8]) -> usize) -> io::Result<u64> {
+        if self.keyring.is_none() {
+            return Ok(0);
+        }
+        let mut rewritten = 0;
+        for id in self.inner.list()? {
+            let id = id?;
+            let _guard = self.rewrite.lock().unwrap();
+            let result = self.is_current(&id).and_then(|current| {
+                if current {
+                    return Ok(false);
+                }
+                let body = self.get(&id)?;
+                let (header, body) = body.split_at(clear_header(&body).min(body.len()));
+                self.put_with_header(&id, header, body).map(|_| true)
+            });
+            match result {
+                Ok(true) => rewritten += 1,
+                Ok(false) => {}
+                // Deleted since it was listed.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => tracing::error!("re-encryption of slice {} failed: {}", id, e),
+            }
+        }
+        Ok(rewritten)
+    }
+}
+
+impl SliceBackend for EncryptingBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        self.put_with_header(id, &[], body)
+    }
+
+    /// `header` is sealed in clear, `read_prefix` and `stat` then read it without opening the slice.
+    fn put_with_header(&self, id: &str, header: &[u8], body: &[u8]) -> io::Result<()> {
+        let start = if header.is_empty() { body } else { header };
+        match &self.keyring {
+            Some(keyring) => self.inner.put(id, &keyring.seal_with_header(header, body)?),
+            // It would be taken for a sealed slice when read back.
+            None if Keyring::is_sealed(start) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("slice {} starts with the sealing magic, it can only be stored with a master key", id),
+            )),
+            None => self.inner.put_with_header(id, header, body),
+        }
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        let stored = self.inner.get(id)?;
+        if !Keyring::is_sealed(&stored) {
+            return Ok(stored);
+        }
+        self.keyring(id)?.open(&stored)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        let _guard = self.rewrite.lock().unwrap();
+        self.inner.delete(id)
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        let stat = self.inner.stat(id)?;
+        let prefix = self.inner.read_prefix(id, Keyring::key_id_prefix_length())?;
+        if !Keyring::is_sealed(&prefix) {
+            return Ok(stat);
+        }
+        let size = Keyring::plaintext_length(&prefix, stat.stored_size).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("sealed slice {} is truncated", id),
+        ))?;
+        Ok(SliceStat { size, stored_size: stat.stored_size })
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        self.inner.list()
+    }
+
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        let _guard = self.rewrite.lock().unwrap();
+        self.inner.quarantine(id)
+    }
+
+    /// Only plaintext slices can be served straight from their file.
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        let path = self.inner.local_path(id)?;
+        match Keyring::is_sealed(&self.inner.read_prefix(id, Keyring::key_id_prefix_length()).ok()?) {
+            false => Some(path),
+            true => None,
+        }
+    }
+
+    /// Plaintext slices are read as far as needed, and so are sealed ones whose clear header covers `length`,
+    /// e.g. for the compression header. Only other sealed ones are read and opened whole.
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        let mut prefix = self.inner.read_prefix(id, length.max(Keyring::header_prefix_length()))?;
+        if !Keyring::is_sealed(&prefix) {
+            prefix.truncate(length);
+            return Ok(prefix);
+        }
+        // Not authenticated before the slice is opened, like the sizes `stat` reports.
+        if let Some(header) = Keyring::clear_header(&prefix).filter(|header| header.len() >= length) {
+            return Ok(header[..length].to_vec());
+        }
+        let mut body = self.keyring(id)?.open(&self.inner.get(id)?)?;
+        body.truncate(length);
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::store::backend::{CompressingBackend, MemoryBackend};
+    use crate::store::backend::compression::CompressionCodec;
+
+    /// A keyring loaded from a key file holding `keys`, `active` among them.
+    fn keyring(active: &str, keys: &[(&str, [u8; 32])]) -> Arc<Keyring> {
+        let keys: serde_json::Map<String, serde_json::Value> = keys
+            .iter()
+            .map(|(id, key)| (id.to_string(), hex::encode(key).into()))
+            .collect();
+        let path = std::env::temp_dir().join(format!("keyring-test-{:016x}.json", rand::random::<u64>()));
+        fs::write(&path, serde_json::json!({ "active": active, "keys": keys }).to_string()).unwrap();
+        let keyring = Keyring::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        Arc::new(keyring)
+    }
+
+    #[test]
+    fn sealed_data_round_trips() {
+        let keyring = keyring("k1", &[("k1", [1; 32])]);
+        let sealed = keyring.seal(b"secret slice").unwrap();
+        assert!(Keyring::is_sealed(&sealed));
+        assert_eq!(Keyring::key_id_of(&sealed), Some("k1"));
+        assert_eq!(Keyring::plaintext_length(&sealed, sealed.len() as u64), Some(12));
+        assert!(!sealed.windows(12).any(|window| window == b"secret slice"));
+        assert_eq!(keyring.open(&sealed).unwrap(), b"secret slice");
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let keyring = keyring("k1", &[("k1", [1; 32])]);
+        let sealed = keyring.seal(b"secret slice").unwrap();
+        // The key id, the wrapped data key, the payload nonce and the ciphertext.
+        for position in [9, 30, sealed.len() - 30, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(keyring.open(&tampered).is_err(), "byte {} was tampered with unnoticed", position);
+        }
+        assert!(keyring.open(&sealed[..sealed.len() - 20]).is_err());
+    }
+
+    #[test]
+    fn wrong_or_missing_key_is_refused() {
+        let sealed = keyring("k1", &[("k1", [1; 32])]).seal(b"secret slice").unwrap();
+        assert!(keyring("k1", &[("k1", [2; 32])]).open(&sealed).is_err());
+        assert!(keyring("k2", &[("k2", [1; 32])]).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let inner = Arc::new(MemoryBackend::default());
+        let plain = EncryptingBackend::new(inner.clone(), None);
+        plain.put("id", b"plain slice").unwrap();
+        assert_eq!(inner.get("id").unwrap(), b"plain slice");
+        assert_eq!(plain.read_prefix("id", 5).unwrap(), b"plain");
+
+        // Written before encryption was enabled.
+        let sealing = EncryptingBackend::new(inner.clone(), Some(keyring("k1", &[("k1", [1; 32])])));
+        assert_eq!(sealing.get("id").unwrap(), b"plain slice");
+        assert_eq!(sealing.stat("id").unwrap().size, 11);
+
+        sealing.put("sealed", b"sealed slice").unwrap();
+        assert!(Keyring::is_sealed(&inner.get("sealed").unwrap()));
+        assert_eq!(sealing.read_prefix("sealed", 6).unwrap(), b"sealed");
+        assert_eq!(sealing.stat("sealed").unwrap().size, 12);
+        assert_eq!(plain.get("sealed").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn clear_header_is_read_without_opening() {
+        let inner = Arc::new(MemoryBackend::default());
+        let keys = keyring("k1", &[("k1", [1; 32])]);
+        let sealing = EncryptingBackend::new(inner.clone(), Some(keys.clone()));
+        sealing.put_with_header("id", b"header", b"secret body").unwrap();
+        let stored = inner.get("id").unwrap();
+        assert_eq!(Keyring::clear_header(&stored), Some(&b"header"[..]));
+        assert!(!stored.windows(11).any(|window| window == b"secret body"));
+        assert_eq!(sealing.get("id").unwrap(), b"headersecret body");
+
+        // Without the key, the header and the sizes can still be read, not the rest.
+        let plain = EncryptingBackend::new(inner.clone(), None);
+        assert_eq!(plain.read_prefix("id", 6).unwrap(), b"header");
+        assert_eq!(plain.stat("id").unwrap().size, 17);
+        assert_eq!(plain.read_prefix("id", 7).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // The header is authenticated along with the body.
+        let mut tampered = stored.clone();
+        tampered[12] ^= 1;
+        assert!(keys.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn compressed_slices_are_stat_ed_without_opening() {
+        let inner = Arc::new(MemoryBackend::default());
+        let sealing = Arc::new(EncryptingBackend::new(inner.clone(), Some(keyring("k1", &[("k1", [1; 32])]))));
+        let body: Vec<u8> = b"slice content ".iter().cycle().take(4096).cloned().collect();
+        CompressingBackend::new(sealing, CompressionCodec::Zstd, 3).put("id", &body).unwrap();
+
+        let keyless = CompressingBackend::new(Arc::new(EncryptingBackend::new(inner.clone(), None)), CompressionCodec::Zstd, 3);
+        let stat = keyless.stat("id").unwrap();
+        assert_eq!(stat.size, body.len() as u64);
+        assert_eq!(stat.stored_size, inner.get("id").unwrap().len() as u64);
+        assert!(keyless.get("id").is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_clear_headers() {
+        let inner = Arc::new(MemoryBackend::default());
+        inner.put("plain", b"headerplain body").unwrap();
+        let sealing = EncryptingBackend::new(inner.clone(), Some(keyring("k1", &[("k1", [1; 32])])));
+        let header_length = |body: &[u8]| if body.starts_with(b"header") { 6 } else { 0 };
+        assert_eq!(sealing.reencrypt(header_length).unwrap(), 1);
+        assert_eq!(Keyring::clear_header(&inner.get("plain").unwrap()), Some(&b"header"[..]));
+        assert_eq!(sealing.get("plain").unwrap(), b"headerplain body");
+    }
+
+    #[test]
+    fn rotation_reencrypts_under_the_active_key() {
+        let inner = Arc::new(MemoryBackend::default());
+        let old = EncryptingBackend::new(inner.clone(), Some(keyring("k1", &[("k1", [1; 32])])));
+        old.put("sealed", b"sealed slice").unwrap();
+        inner.put("plain", b"plain slice").unwrap();
+
+        let rotated = EncryptingBackend::new(inner.clone(), Some(keyring("k2", &[("k1", [1; 32]), ("k2", [2; 32])])));
+        assert_eq!(rotated.get("sealed").unwrap(), b"sealed slice");
+        assert_eq!(rotated.reencrypt(|_| 0).unwrap(), 2);
+        assert_eq!(rotated.reencrypt(|_| 0).unwrap(), 0);
+        for id in ["sealed", "plain"] {
+            assert_eq!(Keyring::key_id_of(&inner.get(id).unwrap()), Some("k2"));
+        }
+
+        // The old key can go once everything is rewritten.
+        let new = EncryptingBackend::new(inner, Some(keyring("k2", &[("k2", [2; 32])])));
+        assert_eq!(new.get("sealed").unwrap(), b"sealed slice");
+        assert_eq!(new.get("plain").unwrap(), b"plain slice");
+    }
+}
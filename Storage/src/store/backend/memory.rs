@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::RwLock;
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+
+/// Keeps slices in memory. Nothing survives a restart, it is meant for tests.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    slices: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+fn not_found(id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("slice {} not found", id))
+}
+
+impl SliceBackend for MemoryBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        self.slices.write().unwrap().insert(id.into(), body.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        self.slices.read().unwrap().get(id).cloned().ok_or_else(|| not_found(id))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.slices.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        let slices = self.slices.read().unwrap();
+        let body = slices.get(id).ok_or_else(|| not_found(id))?;
+        Ok(SliceStat { size: body.len() as u64, stored_size: body.len() as u64 })
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        let ids: Vec<String> = self.slices.read().unwrap().keys().cloned().collect();
+        Ok(Box::new(ids.into_iter().map(Ok)))
+    }
+}
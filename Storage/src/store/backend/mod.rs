@@ -0,0 +1,239 @@
+use std::fmt::Debug;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::ArgEnum;
+use sled::Db;
+
+use crate::ARGS;
+use crate::store::backend::multi_disk::Disk;
+use crate::store::capacity::Watermarks;
+use crate::store::digest::verify_slice;
+use crate::store::keyring::Keyring;
+
+pub mod compression;
+pub mod directory;
+pub mod encryption;
+pub mod memory;
+pub mod multi_disk;
+pub mod segment;
+pub mod sled_blob;
+
+pub use compression::CompressingBackend;
+pub use directory::DirectoryBackend;
+pub use encryption::EncryptingBackend;
+pub use memory::MemoryBackend;
+pub use multi_disk::MultiDiskBackend;
+pub use segment::SegmentBackend;
+pub use sled_blob::SledBlobBackend;
+
+/// Iterator over the ids of stored slices, see `SliceBackend::list`.
+pub type SliceIds = Box<dyn Iterator<Item = io::Result<String>> + Send>;
+
+/// What a backend knows about a stored slice without reading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SliceStat {
+    /// Length of the slice content.
+    pub size: u64,
+    /// Bytes the slice takes in the backend, e.g. after compression.
+    pub stored_size: u64,
+}
+
+/// Where slice content lives on a node.
+///
+/// Backends only store bytes, checking content against the id is left to the callers,
+/// `put_verified` and `get_verified` do both.
+pub trait SliceBackend: Send + Sync + Debug {
+    /// Stores `body` under `id`, replacing any previous content.
+    /// The slice must be durable when this returns.
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()>;
+
+    /// Stores `header` followed by `body` under `id`. Backends transforming what they store keep `header`
+    /// apart, so that a `read_prefix` of at most `header.len()` bytes does not read the whole slice back.
+    fn put_with_header(&self, id: &str, header: &[u8], body: &[u8]) -> io::Result<()> {
+        let mut stored = Vec::with_capacity(header.len() + body.len());
+        stored.extend_from_slice(header);
+        stored.extend_from_slice(body);
+        self.put(id, &stored)
+    }
+
+    /// Reads the whole content of slice `id`.
+    fn get(&self, id: &str) -> io::Result<Vec<u8>>;
+
+    /// Removes slice `id`. Removing a slice that is not stored is not an error,
+    /// a replica may apply the same delete again after a restart.
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat>;
+
+    /// Ids of all stored slices, in no particular order.
+    fn list(&self) -> io::Result<SliceIds>;
+
+    /// Takes a corrupted slice out of service. Backends able to keep it aside for inspection should.
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        self.delete(id)
+    }
+
+    /// Reads the first `length` bytes of slice `id`, or the whole slice if it is shorter.
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        let mut body = self.get(id)?;
+        body.truncate(length);
+        Ok(body)
+    }
+
+    /// Path of a file holding exactly the content of slice `id`, if the backend keeps one.
+    /// Lets the http layer stream and serve ranges straight from the file.
+    fn local_path(&self, _id: &str) -> Option<PathBuf> {
+        None
+    }
+
+    fn put_verified(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        verify_slice(id, body)?;
+        self.put(id, body)
+    }
+
+    fn get_verified(&self, id: &str) -> io::Result<Vec<u8>> {
+        let body = self.get(id)?;
+        verify_slice(id, &body)?;
+        Ok(body)
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceBackendKind {
+    /// One file per slice in a directory tree under each data directory.
+    Directory,
+    /// Slices below `segment_threshold` packed into large segment files, bigger ones as in `Directory`.
+    Segment,
+    /// Slices as values of a sled tree, meant for small slices.
+    Sled,
+    /// Slices in memory, lost on restart. For tests.
+    Memory,
+}
+
+/// Opens the backend selected by `ARGS.slice_backend`, compressing with `ARGS.compression`
+/// and sealing with `keyring`. Starts re-encrypting slices not sealed under the active key.
+/// Directory and segment backends spread slices over the data directories, which are returned too.
+pub fn open_slice_backend(
+    db: &Db,
+    keyring: Option<Arc<Keyring>>,
+) -> io::Result<(Arc<dyn SliceBackend>, Option<Arc<MultiDiskBackend>>)> {
+    let (storage, disks): (Arc<dyn SliceBackend>, _) = match ARGS.slice_backend {
+        SliceBackendKind::Directory | SliceBackendKind::Segment => {
+            let disks = Arc::new(open_disks(db)?);
+            spawn_recount(disks.clone())?;
+            (disks.clone(), Some(disks))
+        }
+        SliceBackendKind::Sled => {
+            // A replicated slice the backend refuses would fence every replica applying it.
+            if ARGS.payload_size > ARGS.sled_max_slice_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "payload size {} is above the sled max slice size {}, slices the node accepts could not be stored",
+                        ARGS.payload_size, ARGS.sled_max_slice_size,
+                    ),
+                ));
+            }
+            let overhead = compression::HEADER_LENGTH + keyring.as_ref().map_or(0, |keyring| keyring.active_overhead());
+            (Arc::new(SledBlobBackend::open(db, ARGS.sled_max_slice_size, overhead)?), None)
+        }
+        SliceBackendKind::Memory => (Arc::new(MemoryBackend::default()), None),
+    };
+    let encrypted = Arc::new(EncryptingBackend::new(storage, keyring));
+    spawn_reencryption(encrypted.clone())?;
+    // Always wrapped, slices compressed before the codec was switched off must stay readable.
+    // Compression goes first, sealed bytes do not compress.
+    let backend = Arc::new(CompressingBackend::new(encrypted, ARGS.compression, ARGS.compression_level));
+    tracing::info!("slice backend: {:?}", backend);
+    Ok((backend, disks))
+}
+
+/// The data directories of `ARGS.data_dirs` and their weights, `storage_location` alone when none is given.
+/// A directory is given as `<path>` or `<path>:<weight>`, the weight defaults to 1.
+pub fn data_directories() -> io::Result<Vec<(String, u64)>> {
+    if ARGS.data_dirs.is_empty() {
+        return Ok(vec![(ARGS.storage_location.clone(), 1)]);
+    }
+    let mut directories: Vec<(String, u64)> = Vec::with_capacity(ARGS.data_dirs.len());
+    for spec in &ARGS.data_dirs {
+        let (root, weight) = match spec.rsplit_once(':') {
+            Some((root, weight)) => {
+                let weight = weight.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid weight in data directory {}", spec))
+                })?;
+                (root, weight)
+            }
+            None => (spec.as_str(), 1),
+        };
+        let root = root.trim_end_matches('/').to_string();
+        if root.is_empty() || weight == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid data directory {}", spec)));
+        }
+        if directories.iter().any(|(other, _)| *other == root) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("data directory {} given twice", root)));
+        }
+        directories.push((root, weight));
+    }
+    Ok(directories)
+}
+
+/// Opens a backend per data directory. A directory failing to open is offline, the node starts with the others.
+fn open_disks(db: &Db) -> io::Result<MultiDiskBackend> {
+    let disks = data_directories()?
+        .into_iter()
+        .map(|(root, weight)| {
+            let backend = open_disk_backend(db, &root);
+            Disk::new(&root, weight, Duration::from_millis(ARGS.slow_disk_millis), backend)
+        })
+        .collect();
+    MultiDiskBackend::open(db, disks, Watermarks::from_args()?)
+}
+
+/// Counts the slices of the data directories on startup and every `ARGS.capacity_scan_interval` seconds.
+fn spawn_recount(disks: Arc<MultiDiskBackend>) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("capacity-recount".into())
+        .spawn(move || loop {
+            if let Err(e) = disks.recount() {
+                tracing::error!("counting slices of the data directories failed: {}", e);
+            }
+            std::thread::sleep(Duration::from_secs(ARGS.capacity_scan_interval));
+        })?;
+    Ok(())
+}
+
+fn open_disk_backend(db: &Db, root: &str) -> io::Result<Arc<dyn SliceBackend>> {
+    let standalone = Arc::new(DirectoryBackend::open(root, ARGS.storage_directory_depth)?);
+    if ARGS.slice_backend != SliceBackendKind::Segment {
+        return Ok(standalone);
+    }
+    // The index of the only directory nodes had before keeps its name.
+    let index_tree = if root == ARGS.storage_location {
+        segment::SEGMENT_INDEX_TREE.to_string()
+    } else {
+        format!("{}:{}", segment::SEGMENT_INDEX_TREE, root)
+    };
+    Ok(Arc::new(SegmentBackend::open(
+        &format!("{}/{}", root, directory::SEGMENT_DIRECTORY),
+        db,
+        &index_tree,
+        standalone,
+        ARGS.segment_threshold,
+        ARGS.segment_size,
+        Duration::from_secs(ARGS.segment_compaction_interval),
+    )?))
+}
+
+fn spawn_reencryption(backend: Arc<EncryptingBackend>) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("slice-reencryption".into())
+        .spawn(move || match backend.reencrypt(compression::header_length) {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("re-encrypted {} slices under the active master key", count),
+            Err(e) => tracing::error!("re-encryption of slices failed: {}", e),
+        })?;
+    Ok(())
+}
@@ -0,0 +1,516 @@
+use std::{fmt, io};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Serialize;
+use sled::{Db, Tree};
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+use crate::store::capacity::{filesystem_usage, LocationCapacity, Watermarks};
+use crate::store::health::{classify, IoErrorClass};
+
+/// Which disk holds which slice, by disk root. Slices missing from it, e.g. written before
+/// the node had several disks, are looked up on every disk and indexed when found.
+const DISK_INDEX_TREE: &str = "disk-index";
+
+/// Weight of the latest operation in the moving average of a disk's latency, as a fraction 1/N.
+const LATENCY_SMOOTHING: u64 = 8;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskState {
+    Online,
+    /// Takes writes only when the fast disks cannot, see `slow_disk_millis`.
+    Slow,
+    /// Out of space, read only until a write fits again.
+    Full,
+    /// The file system went read-only, it stays so until the node restarts.
+    ReadOnly,
+    /// Failing or never opened, neither read nor written until the node restarts.
+    Offline,
+}
+
+impl DiskState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => DiskState::Online,
+            1 => DiskState::Slow,
+            2 => DiskState::Full,
+            3 => DiskState::ReadOnly,
+            _ => DiskState::Offline,
+        }
+    }
+}
+
+/// One data directory of a node, usually a disk of its own.
+#[derive(Debug)]
+pub struct Disk {
+    pub root: String,
+    /// Share of new slices relative to the other disks, at equal free space.
+    pub weight: u64,
+    /// None when the disk could not even be opened.
+    backend: Option<Arc<dyn SliceBackend>>,
+    /// A `DiskState`, never `Slow`: slowness follows the latency.
+    state: AtomicU8,
+    latency_micros: AtomicU64,
+    slow_threshold: Duration,
+    /// Slices on the disk and the bytes they take, recounted by `MultiDiskBackend::recount`.
+    objects: AtomicU64,
+    stored_bytes: AtomicU64,
+    over_watermark: AtomicBool,
+}
+
+impl Disk {
+    /// A disk whose backend is `backend`, offline if it failed to open.
+    /// It counts as slow while its operations take `slow_threshold` on average.
+    pub fn new(root: &str, weight: u64, slow_threshold: Duration, backend: io::Result<Arc<dyn SliceBackend>>) -> Self {
+        let backend = match backend {
+            Ok(backend) => Some(backend),
+            Err(err) => {
+                tracing::error!("disk {} failed to open, it is offline: {}", root, err);
+                None
+            }
+        };
+        let state = if backend.is_some() { DiskState::Online } else { DiskState::Offline };
+        Disk {
+            root: root.trim_end_matches('/').into(),
+            weight,
+            backend,
+            state: AtomicU8::new(state as u8),
+            latency_micros: AtomicU64::new(0),
+            slow_threshold,
+            objects: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+            over_watermark: AtomicBool::new(false),
+        }
+    }
+
+    pub fn state(&self) -> DiskState {
+        match DiskState::from_u8(self.state.load(Ordering::Relaxed)) {
+            DiskState::Online if self.latency() >= self.slow_threshold => DiskState::Slow,
+            state => state,
+        }
+    }
+
+    /// Average latency of the operations on this disk.
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_micros.load(Ordering::Relaxed))
+    }
+
+    /// The backend of a disk that can still be read.
+    fn backend(&self) -> Option<&Arc<dyn SliceBackend>> {
+        self.backend.as_ref().filter(|_| self.state() != DiskState::Offline)
+    }
+
+    fn is_writable(&self) -> bool {
+        matches!(self.state(), DiskState::Online | DiskState::Slow)
+    }
+
+    /// Total and available bytes of the disk, nothing for an offline one.
+    fn usage(&self) -> (u64, u64) {
+        if self.state() == DiskState::Offline {
+            return (0, 0);
+        }
+        filesystem_usage(&self.root).unwrap_or((0, 0))
+    }
+
+    fn account(&self, objects: i64, bytes: i64) {
+        let add = |counter: &AtomicU64, delta: i64| {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                Some(if delta < 0 { value.saturating_sub(delta.unsigned_abs()) } else { value + delta as u64 })
+            });
+        };
+        add(&self.objects, objects);
+        add(&self.stored_bytes, bytes);
+    }
+
+    /// Moves the disk to `state`, unless it is in a worse one already.
+    fn degrade(&self, state: DiskState, err: &io::Error) {
+        let previous = self.state.fetch_max(state as u8, Ordering::Relaxed);
+        if previous < state as u8 {
+            tracing::error!("disk {} is {:?}: {}", self.root, state, err);
+        }
+    }
+
+    /// Runs `operation` on this disk, timing it and degrading the disk when it failed because of the device.
+    fn run<T>(&self, operation: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let started = Instant::now();
+        let result = operation();
+        let sample = started.elapsed().as_micros() as u64;
+        let _ = self.latency_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(average - average / LATENCY_SMOOTHING + sample / LATENCY_SMOOTHING)
+        });
+
+        if let Err(err) = &result {
+            match classify(err) {
+                IoErrorClass::Failed => self.degrade(DiskState::Offline, err),
+                IoErrorClass::ReadOnly => self.degrade(DiskState::ReadOnly, err),
+                IoErrorClass::Full => self.degrade(DiskState::Full, err),
+                IoErrorClass::Other => {}
+            }
+        }
+        result
+    }
+}
+
+/// Returned (wrapped in an `io::Error` of kind `NotFound`) for slices indexed on a disk that is offline.
+/// The scrubber fetches them again from the other members of the group.
+#[derive(Debug)]
+pub struct DiskOffline {
+    pub id: String,
+    pub root: String,
+}
+
+impl fmt::Display for DiskOffline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slice {} is on offline disk {}", self.id, self.root)
+    }
+}
+
+impl Error for DiskOffline {}
+
+/// Tells whether an error means the slice was lost with its disk.
+pub fn is_on_offline_disk(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<DiskOffline>())
+}
+
+/// What `/node-metrics` shows about a disk.
+#[derive(Serialize, Debug, Clone)]
+pub struct DiskMetrics {
+    pub root: String,
+    pub weight: u64,
+    pub state: DiskState,
+    pub latency_micros: u64,
+    pub available_bytes: u64,
+}
+
+/// Where a slice is: its disk, the disk's backend and what the backend says about it.
+type Located<'a> = (&'a Disk, &'a Arc<dyn SliceBackend>, SliceStat);
+
+/// Spreads slices over several disks, each with a backend of its own.
+///
+/// New slices go to a writable disk picked at random, in proportion to its free space times its weight,
+/// slow disks only when no other takes the slice.
+/// A disk failing with an I/O error of the device is marked offline and skipped from then on,
+/// the slices it held are reported missing so the scrubber repairs them onto the other disks.
+/// Disks above the high watermark only take slices when no other disk can, i.e. those replicated by the log.
+#[derive(Debug)]
+pub struct MultiDiskBackend {
+    disks: Vec<Disk>,
+    index: Tree,
+    watermarks: Watermarks,
+}
+
+impl MultiDiskBackend {
+    pub fn open(db: &Db, disks: Vec<Disk>, watermarks: Watermarks) -> io::Result<Self> {
+        if disks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no data directory configured"));
+        }
+        if disks.iter().all(|disk| disk.state() == DiskState::Offline) {
+            return Err(io::Error::new(io::ErrorKind::Other, "every data directory is offline"));
+        }
+        Ok(MultiDiskBackend {
+            disks,
+            index: db.open_tree(DISK_INDEX_TREE)?,
+            watermarks,
+        })
+    }
+
+    pub fn states(&self) -> Vec<DiskState> {
+        self.disks.iter().map(Disk::state).collect()
+    }
+
+    pub fn metrics(&self) -> Vec<DiskMetrics> {
+        self.disks
+            .iter()
+            .map(|disk| {
+                let state = disk.state();
+                DiskMetrics {
+                    root: disk.root.clone(),
+                    weight: disk.weight,
+                    state,
+                    latency_micros: disk.latency().as_micros() as u64,
+                    available_bytes: disk.usage().1,
+                }
+            })
+            .collect()
+    }
+
+    pub fn capacity(&self) -> Vec<LocationCapacity> {
+        self.disks
+            .iter()
+            .map(|disk| {
+                let (total, available) = disk.usage();
+                let mut location = LocationCapacity::new(&disk.root, total, available);
+                location.objects = Some(disk.objects.load(Ordering::Relaxed));
+                location.stored_bytes = Some(disk.stored_bytes.load(Ordering::Relaxed));
+                location.over_watermark = self.is_over_watermark(disk, total, available);
+                location
+            })
+            .collect()
+    }
+
+    /// Tells whether a writable disk is under the high watermark.
+    pub fn accepts_writes(&self) -> bool {
+        self.disks.iter().filter(|disk| disk.is_writable()).any(|disk| {
+            let (total, available) = disk.usage();
+            !self.is_over_watermark(disk, total, available)
+        })
+    }
+
+    fn is_over_watermark(&self, disk: &Disk, total: u64, available: u64) -> bool {
+        self.watermarks.check(&disk.over_watermark, &disk.root, total, available)
+    }
+
+    /// Counts the slices of every disk again, the counts kept up to date by writes drift when
+    /// slices are written while they are counted.
+    pub fn recount(&self) -> io::Result<()> {
+        for disk in &self.disks {
+            let backend = match disk.backend() {
+                Some(backend) => backend,
+                None => continue,
+            };
+            let (mut objects, mut bytes) = (0, 0);
+            for id in disk.run(|| backend.list())? {
+                match backend.stat(&id?) {
+                    Ok(stat) => {
+                        objects += 1;
+                        bytes += stat.stored_size;
+                    }
+                    // Deleted since it was listed.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            disk.objects.store(objects, Ordering::Relaxed);
+            disk.stored_bytes.store(bytes, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn indexed_disk(&self, id: &str) -> io::Result<Option<&Disk>> {
+        Ok(self.index.get(id)?.and_then(|root| self.disks.iter().find(|disk| disk.root.as_bytes() == &*root)))
+    }
+
+    /// The disk holding slice `id`. Slices on an offline disk yield `DiskOffline`.
+    fn locate(&self, id: &str) -> io::Result<Located<'_>> {
+        let indexed = self.indexed_disk(id)?;
+        if let Some(disk) = indexed {
+            match disk.backend() {
+                Some(backend) => match disk.run(|| backend.stat(id)) {
+                    Ok(stat) => return Ok((disk, backend, stat)),
+                    // Moved by an interrupted put, the others are looked at below.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(_) if disk.state() == DiskState::Offline => return Err(offline(id, disk)),
+                    Err(err) => return Err(err),
+                },
+                None => return Err(offline(id, disk)),
+            }
+        }
+
+        for disk in &self.disks {
+            let backend = match disk.backend() {
+                Some(backend) if !indexed.map_or(false, |indexed| std::ptr::eq(indexed, disk)) => backend,
+                _ => continue,
+            };
+            match disk.run(|| backend.stat(id)) {
+                Ok(stat) => {
+                    self.index.insert(id, disk.root.as_bytes())?;
+                    return Ok((disk, backend, stat));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(_) if disk.state() == DiskState::Offline => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("slice {} not found", id)))
+    }
+
+    /// Disks that may take a slice of `size` bytes, in the order they should be tried:
+    /// fast disks first, then slow ones, then those above the high watermark.
+    fn placement(&self, size: u64) -> Vec<&Disk> {
+        let (mut fast, mut slow, mut over) = (Vec::new(), Vec::new(), Vec::new());
+        for disk in &self.disks {
+            let state = disk.state();
+            if matches!(state, DiskState::ReadOnly | DiskState::Offline) {
+                continue;
+            }
+            let (total, available) = disk.usage();
+            // A full disk is given another chance once the slice fits.
+            if state == DiskState::Full && available <= size {
+                continue;
+            }
+            let share = available.saturating_mul(disk.weight);
+            if self.is_over_watermark(disk, total, available) {
+                over.push((disk, share));
+            } else if state == DiskState::Slow {
+                slow.push((disk, share));
+            } else {
+                fast.push((disk, share));
+            }
+        }
+        let mut order = weighted_order(fast);
+        order.extend(weighted_order(slow));
+        order.extend(weighted_order(over));
+        order
+    }
+}
+
+/// Orders disks at random, each coming first in proportion to its share.
+fn weighted_order(mut candidates: Vec<(&Disk, u64)>) -> Vec<&Disk> {
+    let mut rng = rand::thread_rng();
+    let mut order = Vec::with_capacity(candidates.len());
+    while !candidates.is_empty() {
+        let total: u64 = candidates.iter().map(|(_, share)| share).sum();
+        let picked = if total == 0 {
+            0
+        } else {
+            let mut point = rng.gen_range(0..total);
+            candidates.iter().position(|(_, share)| {
+                if point < *share {
+                    return true;
+                }
+                point -= share;
+                false
+            }).unwrap()
+        };
+        order.push(candidates.remove(picked).0);
+    }
+    order
+}
+
+fn offline(id: &str, disk: &Disk) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, DiskOffline { id: id.into(), root: disk.root.clone() })
+}
+
+impl SliceBackend for MultiDiskBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        let previous = match self.locate(id) {
+            Ok((disk, _, stat)) => Some((disk, stat)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        // A slice being replaced stays on its disk, as long as that disk takes it.
+        let mut candidates = self.placement(body.len() as u64);
+        if let Some((previous, _)) = &previous {
+            if let Some(position) = candidates.iter().position(|disk| std::ptr::eq(*disk, *previous)) {
+                let disk = candidates.remove(position);
+                candidates.insert(0, disk);
+            }
+        }
+
+        let mut last_err = io::Error::new(io::ErrorKind::Other, "no data directory takes writes");
+        for disk in candidates {
+            let backend = match disk.backend() {
+                Some(backend) => backend,
+                None => continue,
+            };
+            match disk.run(|| backend.put(id, body)) {
+                Ok(()) => {
+                    // The slice fit, the disk has room again.
+                    let _ = disk.state.compare_exchange(DiskState::Full as u8, DiskState::Online as u8, Ordering::Relaxed, Ordering::Relaxed);
+                    self.index.insert(id, disk.root.as_bytes())?;
+                    match previous {
+                        Some((previous, stat)) if std::ptr::eq(previous, disk) => {
+                            disk.account(0, body.len() as i64 - stat.stored_size as i64);
+                        }
+                        Some((previous, stat)) => {
+                            disk.account(1, body.len() as i64);
+                            if let Some(backend) = previous.backend() {
+                                match previous.run(|| backend.delete(id)) {
+                                    Ok(()) => previous.account(-1, -(stat.stored_size as i64)),
+                                    Err(err) => tracing::warn!("failed to remove old copy of slice {} from {}: {}", id, previous.root, err),
+                                }
+                            }
+                        }
+                        None => disk.account(1, body.len() as i64),
+                    }
+                    return Ok(());
+                }
+                Err(err) if classify(&err) != IoErrorClass::Other => last_err = err,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        let (disk, backend, _) = self.locate(id)?;
+        disk.run(|| backend.get(id))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        let located = self.locate(id).ok().map(|(disk, _, stat)| (disk, stat));
+        // Not only the indexed disk, a copy may be left on another one by an interrupted put.
+        for disk in &self.disks {
+            if let Some(backend) = disk.backend() {
+                if let Err(err) = disk.run(|| backend.delete(id)) {
+                    if classify(&err) == IoErrorClass::Other {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        if let Some((disk, stat)) = located {
+            disk.account(-1, -(stat.stored_size as i64));
+        }
+        self.index.remove(id)?;
+        Ok(())
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        self.locate(id).map(|(_, _, stat)| stat)
+    }
+
+    /// Slices of the readable disks, then those indexed on offline disks, which fail to read.
+    fn list(&self) -> io::Result<SliceIds> {
+        let mut lists: Vec<SliceIds> = Vec::with_capacity(self.disks.len() + 1);
+        for disk in &self.disks {
+            if let Some(backend) = disk.backend() {
+                match disk.run(|| backend.list()) {
+                    Ok(ids) => lists.push(ids),
+                    Err(_) if disk.state() == DiskState::Offline => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let offline: Vec<Vec<u8>> = self.disks
+            .iter()
+            .filter(|disk| disk.state() == DiskState::Offline)
+            .map(|disk| disk.root.as_bytes().to_vec())
+            .collect();
+        let lost = self.index.iter().filter_map(move |entry| match entry {
+            Ok((id, root)) if offline.iter().any(|offline| offline.as_slice() == &*root) => {
+                Some(Ok(String::from_utf8_lossy(&id).into_owned()))
+            }
+            Ok(_) => None,
+            Err(err) => Some(Err(err.into())),
+        });
+        lists.push(Box::new(lost));
+
+        Ok(Box::new(lists.into_iter().flatten()))
+    }
+
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        let (disk, backend, stat) = self.locate(id)?;
+        disk.run(|| backend.quarantine(id))?;
+        disk.account(-1, -(stat.stored_size as i64));
+        self.index.remove(id)?;
+        Ok(())
+    }
+
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        let (disk, backend, _) = self.locate(id)?;
+        disk.run(|| backend.read_prefix(id, length))
+    }
+
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        self.locate(id).ok().and_then(|(_, backend, _)| backend.local_path(id))
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::{fs, io, thread};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sled::{Db, IVec, Tree};
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+
+/// Index of the segments under `storage_location`, those of other data directories are suffixed with their root.
+pub const SEGMENT_INDEX_TREE: &str = "segment-index";
+
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// A sealed segment is compacted once less than this share of its bytes is still referenced.
+const COMPACTION_LIVE_RATIO: f64 = 0.5;
+
+/// Where the content of a packed slice lives: `length` bytes at `offset` of segment `segment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    length: u64,
+}
+
+impl Location {
+    fn to_bytes(self) -> [u8; 24] {
+        let mut bytes = [0; 24];
+        bytes[0..8].copy_from_slice(&self.segment.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != 24 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed segment index entry"));
+        }
+        Ok(Location {
+            segment: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            length: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+/// The segment new slices are appended to.
+#[derive(Debug)]
+struct ActiveSegment {
+    id: u64,
+    file: File,
+    size: u64,
+}
+
+/// Packs slices smaller than `threshold` into large append-only segment files, indexed in sled by id.
+/// Bigger slices get standalone files from the wrapped `standalone` backend.
+///
+/// A record in a segment is `[id length: u32][body length: u64][id][body]`, big endian.
+/// Overwritten and deleted slices leave dead records behind; a background thread rewrites the
+/// live records of mostly dead segments into the active one and removes them.
+#[derive(Debug)]
+pub struct SegmentBackend {
+    inner: Arc<SegmentStore>,
+}
+
+#[derive(Debug)]
+struct SegmentStore {
+    directory: PathBuf,
+    index: Tree,
+    active: Mutex<ActiveSegment>,
+    max_segment_size: u64,
+    threshold: usize,
+    standalone: Arc<dyn SliceBackend>,
+}
+
+impl SegmentBackend {
+    /// Opens the segments under `directory`, indexed in the sled tree `index_tree`, and starts the compaction thread.
+    /// A new segment is started on every open, the tail of the previous one may hold a torn record.
+    pub fn open(
+        directory: &str,
+        db: &Db,
+        index_tree: &str,
+        standalone: Arc<dyn SliceBackend>,
+        threshold: usize,
+        max_segment_size: u64,
+        compaction_interval: Duration,
+    ) -> io::Result<Self> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory)?;
+
+        let next_segment = segment_ids(&directory)?.into_iter().max().map_or(0, |id| id + 1);
+        let active = ActiveSegment {
+            id: next_segment,
+            file: create_segment(&directory, next_segment)?,
+            size: 0,
+        };
+
+        let inner = Arc::new(SegmentStore {
+            directory,
+            index: db.open_tree(index_tree)?,
+            active: Mutex::new(active),
+            max_segment_size,
+            threshold,
+            standalone,
+        });
+
+        let compacted = inner.clone();
+        thread::Builder::new()
+            .name("segment-compaction".into())
+            .spawn(move || loop {
+                thread::sleep(compaction_interval);
+                if let Err(err) = compacted.compact() {
+                    tracing::error!("segment compaction failed: {}", err);
+                }
+            })?;
+
+        Ok(SegmentBackend { inner })
+    }
+}
+
+impl SegmentStore {
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        segment_path(&self.directory, segment)
+    }
+
+    fn location(&self, id: &str) -> io::Result<Option<Location>> {
+        self.index.get(id)?.map(|bytes| Location::from_bytes(&bytes)).transpose()
+    }
+
+    /// Appends records to the active segment and fsyncs it once for the whole batch.
+    fn append(&self, records: &[(&str, &[u8])]) -> io::Result<Vec<Location>> {
+        let mut active = self.active.lock().unwrap();
+        let mut locations = Vec::with_capacity(records.len());
+
+        for (id, body) in records {
+            if active.size >= self.max_segment_size {
+                active.file.sync_data()?;
+                let next = active.id + 1;
+                *active = ActiveSegment { id: next, file: create_segment(&self.directory, next)?, size: 0 };
+            }
+
+            let mut record = Vec::with_capacity(12 + id.len() + body.len());
+            record.extend_from_slice(&(id.len() as u32).to_be_bytes());
+            record.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            record.extend_from_slice(id.as_bytes());
+            record.extend_from_slice(body);
+
+            if let Err(err) = active.file.write_all(&record) {
+                // Whatever part of the record made it to disk is dead, keep appending after it.
+                active.size = active.file.metadata().map_or(active.size, |m| m.len());
+                return Err(err);
+            }
+            locations.push(Location {
+                segment: active.id,
+                offset: active.size + 12 + id.len() as u64,
+                length: body.len() as u64,
+            });
+            active.size += record.len() as u64;
+        }
+
+        active.file.sync_data()?;
+        Ok(locations)
+    }
+
+    fn read(&self, location: Location) -> io::Result<Vec<u8>> {
+        let file = File::open(self.segment_path(location.segment))?;
+        let mut body = vec![0; location.length as usize];
+        file.read_exact_at(&mut body, location.offset)?;
+        Ok(body)
+    }
+
+    /// Rewrites the live records of sealed segments that are mostly dead, then removes them.
+    fn compact(&self) -> io::Result<()> {
+        let active_id = self.active.lock().unwrap().id;
+
+        let mut live_bytes: HashMap<u64, u64> = HashMap::new();
+        for entry in self.index.iter() {
+            let (_, value) = entry?;
+            let location = Location::from_bytes(&value)?;
+            *live_bytes.entry(location.segment).or_default() += location.length;
+        }
+
+        for segment in segment_ids(&self.directory)? {
+            if segment >= active_id {
+                continue;
+            }
+            let size = fs::metadata(self.segment_path(segment))?.len();
+            let live = live_bytes.get(&segment).copied().unwrap_or(0);
+            if live as f64 >= size as f64 * COMPACTION_LIVE_RATIO {
+                continue;
+            }
+            tracing::info!("compacting segment {}: {} of {} bytes live", segment, live, size);
+            self.compact_segment(segment)?;
+        }
+        Ok(())
+    }
+
+    fn compact_segment(&self, segment: u64) -> io::Result<()> {
+        let mut moved: Vec<(IVec, IVec, Vec<u8>)> = Vec::new();
+        for entry in self.index.iter() {
+            let (key, value) = entry?;
+            let location = Location::from_bytes(&value)?;
+            if location.segment == segment {
+                let body = self.read(location)?;
+                moved.push((key, value, body));
+            }
+        }
+
+        let records: Vec<(&str, &[u8])> = moved
+            .iter()
+            .map(|(key, _, body)| (std::str::from_utf8(key).unwrap_or_default(), body.as_slice()))
+            .collect();
+        let locations = self.append(&records)?;
+
+        for ((key, old, _), location) in moved.iter().zip(locations) {
+            // The slice may have been overwritten or deleted meanwhile, then the copy is just dead.
+            let swapped = self.index.compare_and_swap(key, Some(old), Some(&location.to_bytes()[..]))?;
+            if swapped.is_err() {
+                tracing::debug!("slice changed during compaction of segment {}", segment);
+            }
+        }
+        self.index.flush()?;
+
+        fs::remove_file(self.segment_path(segment))?;
+        tracing::info!("segment {} compacted, {} slices moved", segment, moved.len());
+        Ok(())
+    }
+}
+
+impl SliceBackend for SegmentBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        let store = &self.inner;
+        if body.len() >= store.threshold {
+            store.standalone.put(id, body)?;
+            if store.index.remove(id)?.is_some() {
+                store.index.flush()?;
+            }
+            return Ok(());
+        }
+
+        let location = store.append(&[(id, body)])?[0];
+        store.index.insert(id, &location.to_bytes()[..])?;
+        store.index.flush()?;
+        store.standalone.delete(id)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        let store = &self.inner;
+        // A compaction may remove the segment between the index lookup and the read, then look again.
+        for _ in 0..2 {
+            match store.location(id)? {
+                Some(location) => match store.read(location) {
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    result => return result,
+                },
+                None => return store.standalone.get(id),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("segment of slice {} vanished", id)))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        let store = &self.inner;
+        if store.index.remove(id)?.is_some() {
+            store.index.flush()?;
+        }
+        store.standalone.delete(id)
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        match self.inner.location(id)? {
+            Some(location) => Ok(SliceStat { size: location.length, stored_size: location.length }),
+            None => self.inner.standalone.stat(id),
+        }
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        let packed = self.inner.index.iter().keys().map(|key| {
+            let key = key?;
+            String::from_utf8(key.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+        Ok(Box::new(packed.chain(self.inner.standalone.list()?)))
+    }
+
+    fn read_prefix(&self, id: &str, length: usize) -> io::Result<Vec<u8>> {
+        match self.inner.location(id)? {
+            Some(location) => self.inner.read(Location { length: location.length.min(length as u64), ..location }),
+            None => self.inner.standalone.read_prefix(id, length),
+        }
+    }
+
+    fn quarantine(&self, id: &str) -> io::Result<()> {
+        match self.inner.location(id)? {
+            Some(_) => self.delete(id),
+            None => self.inner.standalone.quarantine(id),
+        }
+    }
+
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        match self.inner.location(id) {
+            Ok(None) => self.inner.standalone.local_path(id),
+            _ => None,
+        }
+    }
+}
+
+fn segment_path(directory: &Path, segment: u64) -> PathBuf {
+    directory.join(format!("{:016x}.{}", segment, SEGMENT_EXTENSION))
+}
+
+fn create_segment(directory: &Path, segment: u64) -> io::Result<File> {
+    let file = OpenOptions::new().append(true).create_new(true).open(segment_path(directory, segment))?;
+    File::open(directory)?.sync_all()?;
+    Ok(file)
+}
+
+fn segment_ids(directory: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |extension| extension == SEGMENT_EXTENSION) {
+            if let Some(id) = path.file_stem().and_then(|stem| u64::from_str_radix(&stem.to_string_lossy(), 16).ok()) {
+                ids.push(id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::backend::MemoryBackend;
+
+    fn temp_directory() -> PathBuf {
+        std::env::temp_dir().join(format!("segment-test-{:016x}", rand::random::<u64>()))
+    }
+
+    /// Slices up to 1KB are packed, a segment is sealed past 200 bytes, compaction only runs when asked.
+    fn open(directory: &Path, db: &Db) -> SegmentBackend {
+        SegmentBackend::open(
+            directory.to_str().unwrap(),
+            db,
+            SEGMENT_INDEX_TREE,
+            Arc::new(MemoryBackend::default()),
+            1024,
+            200,
+            Duration::from_secs(3600),
+        ).unwrap()
+    }
+
+    #[test]
+    fn location_round_trips() {
+        let location = Location { segment: 7, offset: 1 << 40, length: 12345 };
+        assert_eq!(Location::from_bytes(&location.to_bytes()).unwrap(), location);
+        assert_eq!(Location::from_bytes(&location.to_bytes()[..23]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn torn_trailing_record_is_left_behind_on_open() {
+        let directory = temp_directory();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let backend = open(&directory, &db);
+        backend.put("a", b"first slice").unwrap();
+
+        // A crash while appending: the header of a record made it to disk, not its id nor its body.
+        let torn_segment = backend.inner.active.lock().unwrap().id;
+        let mut file = OpenOptions::new().append(true).open(segment_path(&directory, torn_segment)).unwrap();
+        file.write_all(&1u32.to_be_bytes()).unwrap();
+        file.write_all(&100u64.to_be_bytes()).unwrap();
+        drop(file);
+        drop(backend);
+
+        let backend = open(&directory, &db);
+        assert_eq!(backend.get("a").unwrap(), b"first slice");
+        assert_ne!(backend.inner.active.lock().unwrap().id, torn_segment);
+
+        backend.put("b", b"second slice").unwrap();
+        assert_eq!(backend.get("b").unwrap(), b"second slice");
+        assert_ne!(backend.inner.location("b").unwrap().unwrap().segment, torn_segment);
+
+        // The torn bytes count as dead, the segment is compacted with its live record kept.
+        backend.inner.compact().unwrap();
+        assert!(!segment_path(&directory, torn_segment).exists());
+        assert_eq!(backend.get("a").unwrap(), b"first slice");
+        assert_eq!(backend.get("b").unwrap(), b"second slice");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_live_records() {
+        let directory = temp_directory();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let backend = open(&directory, &db);
+
+        // Records of 53 bytes, the fourth one seals the first segment.
+        let body = |c: u8| vec![c; 40];
+        for id in ["a", "b", "c", "d"] {
+            backend.put(id, &body(id.as_bytes()[0])).unwrap();
+        }
+        let sealed = backend.inner.location("d").unwrap().unwrap().segment;
+        for id in ["a", "b", "c"] {
+            backend.delete(id).unwrap();
+        }
+        backend.put("e", &body(b'e')).unwrap();
+        assert_ne!(backend.inner.location("e").unwrap().unwrap().segment, sealed);
+
+        backend.inner.compact().unwrap();
+
+        assert!(!segment_path(&directory, sealed).exists());
+        assert_ne!(backend.inner.location("d").unwrap().unwrap().segment, sealed);
+        assert_eq!(backend.get("d").unwrap(), body(b'd'));
+        assert_eq!(backend.get("e").unwrap(), body(b'e'));
+        for id in ["a", "b", "c"] {
+            assert_eq!(backend.get(id).unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+        let mut listed: Vec<String> = backend.list().unwrap().map(Result::unwrap).collect();
+        listed.sort();
+        assert_eq!(listed, vec!["d", "e"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+
+use sled::{Db, Tree};
+
+use crate::store::backend::{SliceBackend, SliceIds, SliceStat};
+
+const SLICE_TREE: &str = "slices";
+
+/// Stores slices as values of a sled tree, in the same database as the raft log.
+/// Saves an inode and a directory walk per slice, which pays off for small slices only:
+/// slices above `max_slice_size` are refused. The limit is on slice content, `overhead` more bytes are taken
+/// for what wrapping backends add, e.g. a compression header and sealing.
+#[derive(Debug)]
+pub struct SledBlobBackend {
+    tree: Tree,
+    max_slice_size: usize,
+    overhead: usize,
+}
+
+impl SledBlobBackend {
+    pub fn open(db: &Db, max_slice_size: usize, overhead: usize) -> io::Result<Self> {
+        Ok(SledBlobBackend {
+            tree: db.open_tree(SLICE_TREE)?,
+            max_slice_size,
+            overhead,
+        })
+    }
+}
+
+fn not_found(id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("slice {} not found", id))
+}
+
+impl SliceBackend for SledBlobBackend {
+    fn put(&self, id: &str, body: &[u8]) -> io::Result<()> {
+        if body.len() > self.max_slice_size + self.overhead {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "slice {} takes {} bytes, sled backend takes at most {} and {} of overhead",
+                    id, body.len(), self.max_slice_size, self.overhead,
+                ),
+            ));
+        }
+        self.tree.insert(id, body)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> io::Result<Vec<u8>> {
+        self.tree.get(id)?.map(|body| body.to_vec()).ok_or_else(|| not_found(id))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        if self.tree.remove(id)?.is_some() {
+            self.tree.flush()?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, id: &str) -> io::Result<SliceStat> {
+        let body = self.tree.get(id)?.ok_or_else(|| not_found(id))?;
+        Ok(SliceStat { size: body.len() as u64, stored_size: body.len() as u64 })
+    }
+
+    fn list(&self) -> io::Result<SliceIds> {
+        let ids = self.tree.iter().keys().map(|key| {
+            let key = key?;
+            String::from_utf8(key.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+        Ok(Box::new(ids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_leaves_room_for_the_overhead() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let backend = SledBlobBackend::open(&db, 100, 16).unwrap();
+        backend.put("full", &[0; 116]).unwrap();
+        assert_eq!(backend.stat("full").unwrap().size, 116);
+        assert_eq!(backend.put("over", &[0; 117]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(backend.get("over").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,155 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use crate::ARGS;
+use crate::store::backend::MultiDiskBackend;
+
+/// Bytes of the file system holding `root`: total and available to the node.
+pub fn filesystem_usage<P: AsRef<Path>>(root: P) -> io::Result<(u64, u64)> {
+    Ok((fs2::total_space(&root)?, fs2::available_space(&root)?))
+}
+
+/// Share of a storage location that may be used, in percent of its file system.
+/// A location reaching `high` takes no new slices from clients until it is back under `low`.
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    pub high: u8,
+    pub low: u8,
+}
+
+impl Watermarks {
+    pub fn new(high: u8, low: u8) -> io::Result<Self> {
+        if low > high || high > 100 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("watermarks should be 0 <= low <= high <= 100, got low {} and high {}", low, high),
+            ));
+        }
+        Ok(Watermarks { high, low })
+    }
+
+    /// `ARGS.high_watermark` and `ARGS.low_watermark`.
+    pub fn from_args() -> io::Result<Self> {
+        Watermarks::new(ARGS.high_watermark, ARGS.low_watermark)
+    }
+
+    /// Updates `over` from the usage of location `root` and returns it.
+    pub fn check(&self, over: &AtomicBool, root: &str, total: u64, available: u64) -> bool {
+        let used = used_percent(total, available);
+        if used >= self.high as u64 {
+            if !over.swap(true, Ordering::Relaxed) {
+                tracing::warn!("{} is {}% full, above the high watermark of {}%", root, used, self.high);
+            }
+        } else if used < self.low as u64 && over.swap(false, Ordering::Relaxed) {
+            tracing::info!("{} is {}% full, back under the low watermark of {}%", root, used, self.low);
+        }
+        over.load(Ordering::Relaxed)
+    }
+}
+
+fn used_percent(total: u64, available: u64) -> u64 {
+    if total == 0 {
+        return 100;
+    }
+    (total.saturating_sub(available) as u128 * 100 / total as u128) as u64
+}
+
+/// Usage of a storage location, see `/node-metrics` and the monitor heartbeat.
+#[derive(Serialize, Debug, Clone)]
+pub struct LocationCapacity {
+    pub root: String,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub free_bytes: u64,
+    /// Slices stored and the bytes they take, None where the backend does not count them.
+    pub objects: Option<u64>,
+    pub stored_bytes: Option<u64>,
+    pub over_watermark: bool,
+}
+
+impl LocationCapacity {
+    pub fn new(root: &str, total: u64, available: u64) -> Self {
+        LocationCapacity {
+            root: root.into(),
+            total_bytes: total,
+            used_bytes: total.saturating_sub(available),
+            free_bytes: available,
+            objects: None,
+            stored_bytes: None,
+            over_watermark: false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CapacityMetrics {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub free_bytes: u64,
+    pub objects: u64,
+    /// False once every location is above the high watermark, client writes get 507.
+    pub accepts_writes: bool,
+    pub locations: Vec<LocationCapacity>,
+}
+
+/// Capacity of the storage locations of a node: its data directories, or `storage_location`
+/// for the backends keeping slices elsewhere than in them.
+#[derive(Debug)]
+pub struct NodeCapacity {
+    disks: Option<Arc<MultiDiskBackend>>,
+    root: String,
+    watermarks: Watermarks,
+    over: AtomicBool,
+}
+
+impl NodeCapacity {
+    pub fn new(disks: Option<Arc<MultiDiskBackend>>, root: &str, watermarks: Watermarks) -> Self {
+        NodeCapacity {
+            disks,
+            root: root.into(),
+            watermarks,
+            over: AtomicBool::new(false),
+        }
+    }
+
+    pub fn locations(&self) -> Vec<LocationCapacity> {
+        if let Some(disks) = &self.disks {
+            return disks.capacity();
+        }
+        match filesystem_usage(&self.root) {
+            Ok((total, available)) => {
+                let mut location = LocationCapacity::new(&self.root, total, available);
+                location.over_watermark = self.watermarks.check(&self.over, &self.root, total, available);
+                vec![location]
+            }
+            Err(err) => {
+                tracing::error!("failed to get usage of {}: {}", self.root, err);
+                vec![]
+            }
+        }
+    }
+
+    /// Tells whether a location is under the high watermark to take a new slice.
+    pub fn accepts_writes(&self) -> bool {
+        match &self.disks {
+            Some(disks) => disks.accepts_writes(),
+            None => self.locations().iter().any(|location| !location.over_watermark),
+        }
+    }
+
+    pub fn metrics(&self) -> CapacityMetrics {
+        let locations = self.locations();
+        CapacityMetrics {
+            total_bytes: locations.iter().map(|location| location.total_bytes).sum(),
+            used_bytes: locations.iter().map(|location| location.used_bytes).sum(),
+            free_bytes: locations.iter().map(|location| location.free_bytes).sum(),
+            objects: locations.iter().filter_map(|location| location.objects).sum(),
+            accepts_writes: self.accepts_writes(),
+            locations,
+        }
+    }
+}
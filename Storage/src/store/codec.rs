@@ -0,0 +1,114 @@
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Binary records start with this byte, which no JSON text starts with, then the format version.
+const BINARY_TAG: u8 = 0;
+
+/// Version of the binary format, bumped whenever the encoding of a record changes.
+/// Version 1 is bincode with its default options.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Content type of binary raft RPC bodies.
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Encodes raft log entries, votes, metas and raft RPC bodies.
+pub fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut data = vec![BINARY_TAG, FORMAT_VERSION];
+    bincode::serialize_into(&mut data, value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(data)
+}
+
+/// Decodes a record written by `encode`, or a JSON one written before the binary format.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    match data {
+        [BINARY_TAG, FORMAT_VERSION, body @ ..] => {
+            bincode::deserialize(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        [BINARY_TAG, version, ..] => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record is in binary format version {}, this node reads version {}", version, FORMAT_VERSION),
+        )),
+        _ => serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Tells whether a record was written as JSON, before the binary format.
+pub fn is_json(data: &[u8]) -> bool {
+    data.first() != Some(&BINARY_TAG)
+}
+
+#[cfg(test)]
+mod tests {
+    use openraft::{Entry, EntryPayload, LeaderId, LogId, Vote};
+
+    use super::*;
+    use crate::{StorageNodeId, StorageNodeRequest, StorageRaftTypeConfig};
+
+    fn log_id() -> LogId<StorageNodeId> {
+        LogId::new(LeaderId::new(3, 1), 42)
+    }
+
+    fn entry() -> Entry<StorageRaftTypeConfig> {
+        Entry {
+            log_id: log_id(),
+            payload: EntryPayload::Normal(StorageNodeRequest::StoreData {
+                id: format!("{}.slice", "ab".repeat(32)),
+                value: vec![0, 1, 2, 255],
+                written_at: 1_650_000_000_000,
+            }),
+        }
+    }
+
+    /// Entries are compared through JSON, they do not implement `PartialEq`.
+    fn same_entry(a: &Entry<StorageRaftTypeConfig>, b: &Entry<StorageRaftTypeConfig>) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let decoded: Entry<StorageRaftTypeConfig> = decode(&encode(&entry()).unwrap()).unwrap();
+        assert!(same_entry(&decoded, &entry()));
+
+        let vote = Vote::new(3, 1);
+        assert_eq!(decode::<Vote<StorageNodeId>>(&encode(&vote).unwrap()).unwrap(), vote);
+        assert_eq!(decode::<LogId<StorageNodeId>>(&encode(&log_id()).unwrap()).unwrap(), log_id());
+    }
+
+    #[test]
+    fn records_are_framed_with_the_format_version() {
+        let data = encode(&log_id()).unwrap();
+        assert_eq!(data[..2], [BINARY_TAG, FORMAT_VERSION]);
+        assert_eq!(data[2..], bincode::serialize(&log_id()).unwrap()[..]);
+        assert!(!is_json(&data));
+    }
+
+    #[test]
+    fn json_records_are_still_read() {
+        let json = serde_json::to_vec(&entry()).unwrap();
+        assert!(is_json(&json));
+        let decoded: Entry<StorageRaftTypeConfig> = decode(&json).unwrap();
+        assert!(same_entry(&decoded, &entry()));
+        // As the migration rewrites it.
+        assert_eq!(encode(&decoded).unwrap(), encode(&entry()).unwrap());
+
+        let vote = Vote::new(3, 1);
+        assert_eq!(decode::<Vote<StorageNodeId>>(&serde_json::to_vec(&vote).unwrap()).unwrap(), vote);
+        let last_purged = serde_json::to_vec(&log_id()).unwrap();
+        assert_eq!(decode::<LogId<StorageNodeId>>(&last_purged).unwrap(), log_id());
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut data = encode(&log_id()).unwrap();
+        data[1] = FORMAT_VERSION + 1;
+        let err = decode::<LogId<StorageNodeId>>(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("version {}", FORMAT_VERSION + 1)));
+
+        assert!(decode::<LogId<StorageNodeId>>(&[BINARY_TAG]).is_err());
+        assert!(decode::<LogId<StorageNodeId>>(&[BINARY_TAG, FORMAT_VERSION, 1]).is_err());
+        assert!(decode::<LogId<StorageNodeId>>(b"{\"not\": \"a log id\"}").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::io;
+
+use serde::Serialize;
+
+use crate::store::backend::SliceBackend;
+use crate::store::digest::{DIGEST_HEX_LENGTH, digest_of_id};
+
+/// Replicated slices are stored once per content, under "<digest>.blob".
+const BLOB_NAME: &str = "blob";
+
+/// How much deduplication saves on this node, exposed by `/node-metrics`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupMetrics {
+    /// Replicated slices, i.e. references to blobs.
+    pub references: u64,
+    pub blobs: u64,
+    /// Bytes the slices would take without deduplication.
+    pub logical_bytes: u64,
+    pub unique_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+/// Digest of a slice as it is counted in the state machine, lower case.
+pub fn content_digest(id: &str) -> Option<String> {
+    digest_of_id(id).map(|digest| digest.to_ascii_lowercase())
+}
+
+/// Id of the blob holding the content of slice `id`.
+pub fn blob_id(id: &str) -> Option<String> {
+    content_digest(id).map(|digest| blob_id_of_digest(&digest))
+}
+
+pub fn blob_id_of_digest(digest: &str) -> String {
+    format!("{}.{}", digest, BLOB_NAME)
+}
+
+pub fn is_blob_id(id: &str) -> bool {
+    digest_of_id(id).is_some() && &id[DIGEST_HEX_LENGTH + 1..] == BLOB_NAME
+}
+
+/// Where the content of slice `id` is stored: its blob, or the slice id itself
+/// for slices written before deduplication.
+pub fn stored_id(backend: &dyn SliceBackend, id: &str) -> String {
+    match blob_id(id) {
+        Some(blob) => match backend.stat(&blob) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => id.to_string(),
+            _ => blob,
+        },
+        None => id.to_string(),
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt, io};
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Length of the hex encoded SHA-256 digest every slice id starts with.
+pub const DIGEST_HEX_LENGTH: usize = 64;
+
+/// Returned (wrapped in an `io::Error` of kind `InvalidData`) when slice content
+/// does not hash to the digest carried by its id.
+#[derive(Debug)]
+pub struct SliceCorrupted {
+    pub id: String,
+    pub actual_digest: String,
+}
+
+impl fmt::Display for SliceCorrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slice {} is corrupted, content digest is {}", self.id, self.actual_digest)
+    }
+}
+
+impl Error for SliceCorrupted {}
+
+/// Returns the digest part of a slice id.
+/// A valid id is 64 hex chars, a '.' and a non-empty object name, e.g. "ab..ef.objectname".
+pub fn digest_of_id(id: &str) -> Option<&str> {
+    if id.len() < DIGEST_HEX_LENGTH + 2 || !id.is_ascii() {
+        return None;
+    }
+    let (digest, rest) = id.split_at(DIGEST_HEX_LENGTH);
+    if !rest.starts_with('.') || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(digest)
+}
+
+/// Hex encoded SHA-256 of slice content.
+pub fn slice_digest(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// Checks `body` against the digest carried by `id`.
+pub fn verify_slice(id: &str, body: &[u8]) -> io::Result<()> {
+    check_digest(id, slice_digest(body))
+}
+
+/// Streams the file at `path` through SHA-256 and checks it against `id`,
+/// the slice is never held in memory as a whole.
+pub fn verify_slice_file(id: &str, path: &Path) -> io::Result<()> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    check_digest(id, hex::encode(hasher.finalize()))
+}
+
+fn check_digest(id: &str, actual_digest: String) -> io::Result<()> {
+    let expected = digest_of_id(id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid slice id {}", id)))?;
+    if expected.eq_ignore_ascii_case(&actual_digest) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, SliceCorrupted { id: id.into(), actual_digest }))
+    }
+}
+
+/// Tells whether an error means the slice content is corrupted.
+pub fn is_corrupted(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<SliceCorrupted>())
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::{ARGS, StorageNodeId};
+use crate::store::digest::{DIGEST_HEX_LENGTH, digest_of_id, slice_digest};
+use crate::store::peer;
+use crate::store::scrub::run_blocking;
+use crate::store::StorageNodeFileStore;
+
+/// Shard ids are "<shard digest>.ec<index>.<slice id>".
+const SHARD_MARKER: &str = ".ec";
+
+/// How an erasure coded slice is split into `data_shards` + `parity_shards` shards of `shard_size` bytes,
+/// any `data_shards` of which rebuild the slice.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErasureLayout {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub shard_size: u64,
+    /// Data shards first, then parity shards.
+    pub shards: Vec<ShardPlacement>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardPlacement {
+    pub id: String,
+    /// Member of the raft group holding the shard.
+    pub node: StorageNodeId,
+}
+
+impl ErasureLayout {
+    pub fn local_shards(&self, node: StorageNodeId) -> impl Iterator<Item = &ShardPlacement> {
+        self.shards.iter().filter(move |shard| shard.node == node)
+    }
+}
+
+/// Shards are stored like slices, under an id starting with their own digest,
+/// so backends, `get_verified` and the scrubber handle them as usual.
+pub fn shard_id(slice_id: &str, index: usize, shard: &[u8]) -> String {
+    format!("{}{}{}.{}", slice_digest(shard), SHARD_MARKER, index, slice_id)
+}
+
+/// Slice id and index of a shard id, None for ids of whole slices.
+pub fn parse_shard_id(id: &str) -> Option<(&str, usize)> {
+    let rest = id.get(DIGEST_HEX_LENGTH..)?.strip_prefix(SHARD_MARKER)?;
+    let (index, slice_id) = rest.split_once('.')?;
+    digest_of_id(slice_id)?;
+    Some((slice_id, index.parse().ok()?))
+}
+
+fn codec(data_shards: usize, parity_shards: usize) -> io::Result<ReedSolomon> {
+    ReedSolomon::new(data_shards, parity_shards).map_err(codec_error)
+}
+
+fn codec_error(err: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("erasure coding failed: {:?}", err))
+}
+
+/// Splits `body` into shards and places them round robin on `members`, starting at a member picked
+/// by the slice digest so that parity does not always land on the same nodes.
+/// Groups smaller than the number of shards get several shards per node, and tolerate fewer losses.
+pub fn encode(
+    slice_id: &str,
+    body: &[u8],
+    data_shards: usize,
+    parity_shards: usize,
+    members: &[StorageNodeId],
+) -> io::Result<(ErasureLayout, Vec<Vec<u8>>)> {
+    if members.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "no member to place shards on"));
+    }
+    let codec = codec(data_shards, parity_shards)?;
+    let shard_size = ((body.len() + data_shards - 1) / data_shards).max(1);
+
+    let mut shards: Vec<Vec<u8>> = (0..data_shards + parity_shards)
+        .map(|index| {
+            let start = (index * shard_size).min(body.len());
+            let end = ((index + 1) * shard_size).min(body.len());
+            let mut shard = if index < data_shards { body[start..end].to_vec() } else { Vec::new() };
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect();
+    codec.encode(&mut shards).map_err(codec_error)?;
+
+    let first = digest_of_id(slice_id).map_or(0, |digest| usize::from_str_radix(&digest[..2], 16).unwrap_or(0));
+    let placements = shards.iter()
+                           .enumerate()
+                           .map(|(index, shard)| ShardPlacement {
+                               id: shard_id(slice_id, index, shard),
+                               node: members[(first + index) % members.len()],
+                           })
+                           .collect();
+
+    let layout = ErasureLayout { data_shards, parity_shards, shard_size: shard_size as u64, shards: placements };
+    Ok((layout, shards))
+}
+
+/// Rebuilds the content of a `size` bytes slice from at least `data_shards` of its shards.
+pub fn decode(layout: &ErasureLayout, size: u64, mut shards: Vec<Option<Vec<u8>>>) -> io::Result<Vec<u8>> {
+    codec(layout.data_shards, layout.parity_shards)?.reconstruct_data(&mut shards).map_err(codec_error)?;
+    let mut body: Vec<u8> = shards.into_iter().take(layout.data_shards).flatten().flatten().collect();
+    body.truncate(size as usize);
+    Ok(body)
+}
+
+/// Addresses of the nodes holding the shards of `layout`.
+async fn shard_addrs(store: &Arc<StorageNodeFileStore>, layout: &ErasureLayout) -> BTreeMap<StorageNodeId, String> {
+    let state_machine = store.state_machine.read().await;
+    let membership = &state_machine.last_membership.membership;
+    layout.shards
+          .iter()
+          .filter_map(|shard| membership.get_node(&shard.node).map(|node| (shard.node, node.addr.clone())))
+          .collect()
+}
+
+/// Collects verified shards, local ones first, until `wanted` of them are found.
+/// Shards that could not be read are None.
+async fn collect_shards(store: &Arc<StorageNodeFileStore>, layout: &ErasureLayout, wanted: usize) -> Vec<Option<Vec<u8>>> {
+    let addrs = shard_addrs(store, layout).await;
+    let mut shards = vec![None; layout.shards.len()];
+    let mut found = 0;
+
+    let local = layout.shards.iter().enumerate().filter(|(_, shard)| shard.node == ARGS.node_id);
+    let remote = layout.shards.iter().enumerate().filter(|(_, shard)| shard.node != ARGS.node_id);
+    for (index, shard) in local.chain(remote) {
+        if found == wanted {
+            break;
+        }
+        let result = if shard.node == ARGS.node_id {
+            let backend = store.backend.clone();
+            let id = shard.id.clone();
+            run_blocking(move || backend.get_verified(&id)).await
+        } else {
+            match addrs.get(&shard.node) {
+                Some(addr) => peer::fetch(addr, "shard", &shard.id).await,
+                None => Err(io::Error::new(io::ErrorKind::NotFound, format!("node {} is not a member", shard.node))),
+            }
+        };
+        match result {
+            Ok(body) => {
+                shards[index] = Some(body);
+                found += 1;
+            }
+            Err(err) => tracing::warn!("shard {} on node {} unavailable: {}", shard.id, shard.node, err),
+        }
+    }
+    shards
+}
+
+/// Reads an erasure coded slice, from any `data_shards` of its shards.
+pub async fn read_erasure_coded(store: &Arc<StorageNodeFileStore>, layout: &ErasureLayout, size: u64) -> io::Result<Vec<u8>> {
+    let shards = collect_shards(store, layout, layout.data_shards).await;
+    let available = shards.iter().filter(|shard| shard.is_some()).count();
+    if available < layout.data_shards {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("only {} of the {} shards needed are available", available, layout.data_shards),
+        ));
+    }
+    let layout = layout.clone();
+    run_blocking(move || decode(&layout, size, shards)).await
+}
+
+/// Stores every shard on the node it is placed on, this node included.
+pub async fn push_shards(
+    store: &Arc<StorageNodeFileStore>,
+    layout: &ErasureLayout,
+    shards: Vec<Vec<u8>>,
+) -> io::Result<()> {
+    let addrs = shard_addrs(store, layout).await;
+
+    for (placement, shard) in layout.shards.iter().zip(shards) {
+        if placement.node == ARGS.node_id {
+            store.collector.keep(&placement.id);
+            let backend = store.backend.clone();
+            let id = placement.id.clone();
+            run_blocking(move || backend.put_verified(&id, &shard)).await?;
+            continue;
+        }
+        let addr = addrs.get(&placement.node).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("node {} is not a member", placement.node))
+        })?;
+        peer::store(addr, "shard", &placement.id, shard).await?;
+    }
+    Ok(())
+}
+
+/// Rebuilds the shards of an erasure coded slice placed on this node that are missing.
+/// Returns how many were rebuilt.
+pub async fn rebuild_local_shards(store: &Arc<StorageNodeFileStore>, layout: &ErasureLayout) -> io::Result<usize> {
+    let backend = store.backend.clone();
+    let local: Vec<(usize, String)> = layout.shards
+                                            .iter()
+                                            .enumerate()
+                                            .filter(|(_, shard)| shard.node == ARGS.node_id)
+                                            .map(|(index, shard)| (index, shard.id.clone()))
+                                            .collect();
+    let missing = run_blocking(move || {
+        let mut missing = Vec::new();
+        for (index, id) in local {
+            match backend.stat(&id) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => missing.push((index, id)),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(missing)
+    }).await?;
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    let mut shards = collect_shards(store, layout, layout.data_shards).await;
+    let (backend, data_shards, parity_shards) = (store.backend.clone(), layout.data_shards, layout.parity_shards);
+    // Reconstruction is CPU bound, it runs off the executor along with the writes.
+    run_blocking(move || {
+        codec(data_shards, parity_shards)?.reconstruct(&mut shards).map_err(codec_error)?;
+        for (index, id) in &missing {
+            let shard = shards[*index].take().unwrap();
+            backend.put_verified(id, &shard)?;
+            tracing::info!("shard {} rebuilt", id);
+        }
+        Ok(missing.len())
+    }).await
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::store::backend::SliceBackend;
+
+/// Blobs and shards nothing refers to, and since when. They are only deleted after a grace period:
+/// a write in flight may be about to refer to them, a blob is reused by every slice with its content.
+/// Whoever is going to rely on one calls `keep` first, which starts its grace period over.
+/// Kept in memory, a restart starts every grace period over.
+#[derive(Debug)]
+pub struct GarbageCollector {
+    grace_period: Duration,
+    unreferenced: Mutex<HashMap<String, Instant>>,
+}
+
+impl GarbageCollector {
+    pub fn new(grace_period: Duration) -> Self {
+        GarbageCollector { grace_period, unreferenced: Mutex::new(HashMap::new()) }
+    }
+
+    /// Records that nothing refers to `id` any more, unless recorded already.
+    pub fn mark(&self, id: &str) {
+        self.unreferenced.lock().unwrap().entry(id.to_string()).or_insert_with(Instant::now);
+    }
+
+    /// Takes `id` off the garbage, it is referred to again or about to be.
+    pub fn keep(&self, id: &str) {
+        self.unreferenced.lock().unwrap().remove(id);
+    }
+
+    /// Deletes `id`, which nothing refers to, once it has been so for the grace period.
+    /// Marks it otherwise. Returns whether it was deleted.
+    pub fn collect(&self, backend: &dyn SliceBackend, id: &str) -> io::Result<bool> {
+        // Held while deleting, so that a `keep` either comes first and saves it,
+        // or comes after and finds it gone.
+        let mut unreferenced = self.unreferenced.lock().unwrap();
+        let since = *unreferenced.entry(id.to_string()).or_insert_with(Instant::now);
+        if since.elapsed() < self.grace_period {
+            return Ok(false);
+        }
+        unreferenced.remove(id);
+        match backend.delete(id) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::backend::MemoryBackend;
+
+    #[test]
+    fn garbage_is_deleted_after_the_grace_period() {
+        let backend = MemoryBackend::default();
+        backend.put("blob", b"content").unwrap();
+
+        let collector = GarbageCollector::new(Duration::from_secs(3600));
+        assert!(!collector.collect(&backend, "blob").unwrap());
+        assert!(backend.get("blob").is_ok());
+
+        let collector = GarbageCollector::new(Duration::ZERO);
+        assert!(collector.collect(&backend, "blob").unwrap());
+        assert_eq!(backend.get("blob").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn kept_garbage_starts_over() {
+        let backend = MemoryBackend::default();
+        backend.put("blob", b"content").unwrap();
+
+        let collector = GarbageCollector::new(Duration::from_millis(50));
+        collector.mark("blob");
+        std::thread::sleep(Duration::from_millis(60));
+        collector.keep("blob");
+        assert!(!collector.collect(&backend, "blob").unwrap());
+        assert!(backend.get("blob").is_ok());
+    }
+}
@@ -0,0 +1,126 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::store::backend::MultiDiskBackend;
+use crate::store::backend::multi_disk::DiskState;
+
+/// Linux errno values telling what went wrong with a device.
+const EIO: i32 = 5;
+const ENXIO: i32 = 6;
+const ENODEV: i32 = 19;
+const ENOSPC: i32 = 28;
+const EROFS: i32 = 30;
+const EDQUOT: i32 = 122;
+
+/// How an I/O error reflects on the disk it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoErrorClass {
+    /// The device is failing, nothing on it can be trusted any more.
+    Failed,
+    /// The device is out of space, it can still be read.
+    Full,
+    /// The file system was remounted read-only, usually after errors.
+    ReadOnly,
+    /// Nothing wrong with the disk, e.g. a missing or corrupted slice.
+    Other,
+}
+
+pub fn classify(err: &io::Error) -> IoErrorClass {
+    match err.raw_os_error() {
+        Some(EIO) | Some(ENXIO) | Some(ENODEV) => IoErrorClass::Failed,
+        Some(ENOSPC) | Some(EDQUOT) => IoErrorClass::Full,
+        Some(EROFS) => IoErrorClass::ReadOnly,
+        _ => IoErrorClass::Other,
+    }
+}
+
+/// Tells whether an error comes from the device rather than from the slice.
+pub fn is_device_error(err: &io::Error) -> bool {
+    classify(err) != IoErrorClass::Other
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthState {
+    Healthy,
+    /// Some disks are offline, slow or read-only, the others take the writes.
+    Degraded,
+    /// No new slice can be stored: no disk takes writes, or the raft log failed a write.
+    ReadOnly,
+}
+
+impl HealthState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HealthState::Healthy => "healthy",
+            HealthState::Degraded => "degraded",
+            HealthState::ReadOnly => "read-only",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthMetrics {
+    pub state: HealthState,
+    /// Why the node went read-only, if it did for another reason than its disks.
+    pub fenced: Option<String>,
+}
+
+/// Health of the storage of a node, derived from its disks and from failures of the raft log and state machine.
+/// Reported in the monitor heartbeat; an unhealthy leader hands leadership over.
+#[derive(Debug, Default)]
+pub struct NodeHealth {
+    disks: Option<Arc<MultiDiskBackend>>,
+    fenced: Mutex<Option<String>>,
+}
+
+impl NodeHealth {
+    pub fn new(disks: Option<Arc<MultiDiskBackend>>) -> Self {
+        NodeHealth {
+            disks,
+            fenced: Mutex::new(None),
+        }
+    }
+
+    /// Makes the node read-only until it restarts, a write it was asked to make durable did not happen.
+    pub fn fence(&self, reason: String) {
+        let mut fenced = self.fenced.lock().unwrap();
+        if fenced.is_none() {
+            tracing::error!("node is read-only: {}", reason);
+            *fenced = Some(reason);
+        }
+    }
+
+    /// Records a failed write of slice or log data, fencing the node if the device is to blame.
+    pub fn report_write_failure(&self, what: &str, err: &io::Error) {
+        if is_device_error(err) {
+            self.fence(format!("{}: {}", what, err));
+        }
+    }
+
+    pub fn state(&self) -> HealthState {
+        if self.fenced.lock().unwrap().is_some() {
+            return HealthState::ReadOnly;
+        }
+        let disks = match &self.disks {
+            Some(disks) => disks.states(),
+            None => return HealthState::Healthy,
+        };
+        if !disks.iter().any(|state| matches!(state, DiskState::Online | DiskState::Slow)) {
+            HealthState::ReadOnly
+        } else if disks.iter().any(|state| *state != DiskState::Online) {
+            HealthState::Degraded
+        } else {
+            HealthState::Healthy
+        }
+    }
+
+    pub fn metrics(&self) -> HealthMetrics {
+        HealthMetrics {
+            state: self.state(),
+            fenced: self.fenced.lock().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::{fmt, fs, io};
+
+use aes_gcm::{Aes256Gcm, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use serde::Deserialize;
+
+use crate::ARGS;
+
+/// Sealed data starts with this magic and a format version.
+const MAGIC: &[u8; 6] = b"HADSSE";
+const FORMAT_VERSION: u8 = 2;
+/// Format of data sealed before it could carry a clear header, still opened.
+const FORMAT_VERSION_WITHOUT_HEADER: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+
+/// Layout of the master key file, keys are hex encoded 256 bits AES keys:
+/// `{"active": "2022-05", "keys": {"2022-04": "9f..", "2022-05": "3c.."}}`
+/// Rotating is adding a key, making it active and restarting the node; old keys must stay
+/// in the file until the re-encryption job has rewritten everything sealed under them.
+#[derive(Deserialize)]
+struct KeyFile {
+    active: String,
+    keys: BTreeMap<String, String>,
+}
+
+/// Master keys loaded from the local key file. Data is sealed with AES-256-GCM under a random
+/// data key per blob, the data key itself is wrapped by the active master key.
+///
+/// A sealed blob is `[magic][version: u8][key id length: u8][key id][header length: u8][header]
+/// [nonce][wrapped data key + tag][nonce][ciphertext + tag]`. The header is left in clear, e.g. the compression
+/// header of a slice so that it can be stat'ed without being opened, and authenticated along with the ciphertext.
+/// Version 1 blobs have no header length nor header.
+pub struct Keyring {
+    active: String,
+    keys: BTreeMap<String, Aes256Gcm>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+         .field("active", &self.active)
+         .field("keys", &self.keys.keys().collect::<Vec<_>>())
+         .finish()
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn cipher_error(_: aes_gcm::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "authentication of sealed data failed")
+}
+
+impl Keyring {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let file: KeyFile = serde_json::from_slice(&fs::read(path)?).map_err(invalid_data)?;
+
+        let mut keys = BTreeMap::new();
+        for (id, key) in file.keys {
+            if id.is_empty() || id.len() > u8::MAX as usize {
+                return Err(invalid_data(format!("key id {:?} should be 1 to 255 bytes long", id)));
+            }
+            let key = hex::decode(key.trim()).map_err(invalid_data)?;
+            let cipher = Aes256Gcm::new_from_slice(&key)
+                .map_err(|_| invalid_data(format!("key {} should be {} bytes long", id, KEY_LENGTH)))?;
+            keys.insert(id, cipher);
+        }
+        if !keys.contains_key(&file.active) {
+            return Err(invalid_data(format!("active key {} is not in the key file", file.active)));
+        }
+
+        Ok(Keyring { active: file.active, keys })
+    }
+
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    pub fn is_sealed(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Id of the master key `sealed` was sealed under, None if it is not sealed.
+    pub fn key_id_of(sealed: &[u8]) -> Option<&str> {
+        if !Self::is_sealed(sealed) || sealed.len() < MAGIC.len() + 2 {
+            return None;
+        }
+        let length = sealed[MAGIC.len() + 1] as usize;
+        let start = MAGIC.len() + 2;
+        sealed.get(start..start + length).and_then(|id| std::str::from_utf8(id).ok())
+    }
+
+    /// Length of a header long enough for `key_id_of` to find the key id.
+    pub const fn key_id_prefix_length() -> usize {
+        MAGIC.len() + 2 + u8::MAX as usize
+    }
+
+    /// Length of a prefix long enough for `clear_header` to find the header.
+    pub const fn header_prefix_length() -> usize {
+        Self::key_id_prefix_length() + 1 + u8::MAX as usize
+    }
+
+    /// The clear header of `sealed`, None if it is not sealed, has no header or `sealed` stops before its end.
+    pub fn clear_header(sealed: &[u8]) -> Option<&[u8]> {
+        if sealed.get(MAGIC.len()) != Some(&FORMAT_VERSION) {
+            return None;
+        }
+        let offset = MAGIC.len() + 2 + Self::key_id_of(sealed)?.len();
+        let length = *sealed.get(offset)? as usize;
+        sealed.get(offset + 1..offset + 1 + length)
+    }
+
+    /// Bytes sealing adds to the plaintext, with a key id of `key_id_length` bytes.
+    /// The clear header counts as plaintext, `open` returns it in front of the rest.
+    pub const fn overhead(key_id_length: usize) -> usize {
+        MAGIC.len() + 3 + key_id_length + 2 * NONCE_LENGTH + KEY_LENGTH + 2 * TAG_LENGTH
+    }
+
+    /// Bytes sealing adds to the plaintext under the active key.
+    pub fn active_overhead(&self) -> usize {
+        Self::overhead(self.active.len())
+    }
+
+    /// Length of the plaintext of a `sealed_length` bytes blob starting with `prefix`.
+    pub fn plaintext_length(prefix: &[u8], sealed_length: u64) -> Option<u64> {
+        let key_id = Self::key_id_of(prefix)?;
+        let overhead = match prefix[MAGIC.len()] {
+            FORMAT_VERSION_WITHOUT_HEADER => Self::overhead(key_id.len()) - 1,
+            _ => Self::overhead(key_id.len()),
+        };
+        sealed_length.checked_sub(overhead as u64)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.seal_with_header(&[], plaintext)
+    }
+
+    /// Seals `header` followed by `body`, leaving `header` readable by `clear_header` without opening the blob.
+    pub fn seal_with_header(&self, header: &[u8], body: &[u8]) -> io::Result<Vec<u8>> {
+        if header.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("clear header of {} bytes is too long", header.len())));
+        }
+        let master = &self.keys[&self.active];
+        let data_key: [u8; KEY_LENGTH] = rand::random();
+        let key_nonce: [u8; NONCE_LENGTH] = rand::random();
+        let payload_nonce: [u8; NONCE_LENGTH] = rand::random();
+
+        let mut sealed = Vec::with_capacity(self.active_overhead() + header.len() + body.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(FORMAT_VERSION);
+        sealed.push(self.active.len() as u8);
+        sealed.extend_from_slice(self.active.as_bytes());
+        sealed.push(header.len() as u8);
+        sealed.extend_from_slice(header);
+
+        let wrapped = master
+            .encrypt(Nonce::from_slice(&key_nonce), Payload { msg: &data_key, aad: self.active.as_bytes() })
+            .map_err(cipher_error)?;
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&wrapped);
+
+        // The whole header is authenticated along with the payload.
+        let cipher = Aes256Gcm::new_from_slice(&data_key).unwrap();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&payload_nonce), Payload { msg: body, aad: &sealed })
+            .map_err(cipher_error)?;
+        sealed.extend_from_slice(&payload_nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let truncated = || invalid_data("sealed data is truncated");
+        if !Self::is_sealed(sealed) {
+            return Err(invalid_data("data is not sealed"));
+        }
+        let version = *sealed.get(MAGIC.len()).ok_or_else(truncated)?;
+        if version != FORMAT_VERSION && version != FORMAT_VERSION_WITHOUT_HEADER {
+            return Err(invalid_data(format!("unknown sealing format {}", version)));
+        }
+        let key_id = Self::key_id_of(sealed).ok_or_else(truncated)?;
+        let master = self.keys
+                         .get(key_id)
+                         .ok_or_else(|| invalid_data(format!("master key {} is not in the key file", key_id)))?;
+
+        let mut offset = MAGIC.len() + 2 + key_id.len();
+        let clear_header = match version {
+            FORMAT_VERSION => {
+                let length = *sealed.get(offset).ok_or_else(truncated)? as usize;
+                offset += 1 + length;
+                sealed.get(offset - length..offset).ok_or_else(truncated)?
+            }
+            _ => &[][..],
+        };
+        let key_nonce = sealed.get(offset..offset + NONCE_LENGTH).ok_or_else(truncated)?;
+        offset += NONCE_LENGTH;
+        let wrapped = sealed.get(offset..offset + KEY_LENGTH + TAG_LENGTH).ok_or_else(truncated)?;
+        offset += KEY_LENGTH + TAG_LENGTH;
+        let header = &sealed[..offset];
+        let payload_nonce = sealed.get(offset..offset + NONCE_LENGTH).ok_or_else(truncated)?;
+        let ciphertext = &sealed[offset + NONCE_LENGTH..];
+
+        let data_key = master
+            .decrypt(Nonce::from_slice(key_nonce), Payload { msg: wrapped, aad: key_id.as_bytes() })
+            .map_err(cipher_error)?;
+        let cipher = Aes256Gcm::new_from_slice(&data_key).map_err(invalid_data)?;
+        let body = cipher
+            .decrypt(Nonce::from_slice(payload_nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(cipher_error)?;
+        if clear_header.is_empty() {
+            return Ok(body);
+        }
+        let mut plaintext = Vec::with_capacity(clear_header.len() + body.len());
+        plaintext.extend_from_slice(clear_header);
+        plaintext.extend_from_slice(&body);
+        Ok(plaintext)
+    }
+}
+
+/// Loads the keyring from `ARGS.master_key_file`, None when encryption is not configured.
+pub fn load_keyring() -> io::Result<Option<Arc<Keyring>>> {
+    match &ARGS.master_key_file {
+        Some(path) => {
+            let keyring = Keyring::load(path)?;
+            tracing::info!("encryption at rest enabled: {:?}", keyring);
+            Ok(Some(Arc::new(keyring)))
+        }
+        None => Ok(None),
+    }
+}
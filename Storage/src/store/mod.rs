@@ -1,8 +1,13 @@
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
-use std::io::Cursor;
+use std::io;
+use std::fs;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::ops::{Bound, RangeBounds};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use openraft::{AnyError};
 use openraft::async_trait::async_trait;
@@ -25,14 +30,39 @@ use openraft::Vote;
 use serde::{Deserialize};
 use serde::Serialize;
 use sled::{Db, IVec};
+use tokio::fs::File;
 use tokio::sync::RwLock;
 
 use crate::{ARGS, StorageNodeId};
+use crate::error::NodeError;
 use crate::StorageRaftTypeConfig;
-
-pub mod fs_io;
-
-//TODO: try delete all unwraps
+use crate::store::backend::SliceBackend;
+use crate::store::dedup::{blob_id, blob_id_of_digest, content_digest, is_blob_id};
+use crate::store::digest::{digest_of_id, slice_digest};
+use crate::store::erasure::ErasureLayout;
+use crate::store::gc::GarbageCollector;
+use crate::store::health::NodeHealth;
+use crate::store::keyring::Keyring;
+use crate::store::snapshot::{EntryKind, load_latest_snapshot, partial_path, remove_snapshot, save_snapshot_meta, snapshot_directory, snapshot_path, SnapshotEntry, SnapshotReader, write_snapshot};
+use crate::store::scrub::run_blocking;
+use crate::store::transfer::{ensure_blobs, member_addrs, members};
+
+pub mod backend;
+pub mod capacity;
+pub mod codec;
+pub mod dedup;
+pub mod digest;
+pub mod erasure;
+pub mod gc;
+pub mod health;
+pub mod keyring;
+pub mod peer;
+pub mod scrub;
+pub mod snapshot;
+pub mod state_machine;
+pub mod transfer;
+
+pub use state_machine::StorageNodeStoreStateMachine;
 
 /**
  * Here you will set the types of request that will interact with the raft nodes.
@@ -42,7 +72,35 @@ pub mod fs_io;
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageNodeRequest {
-    StoreData { id: String, value: Vec<u8> },
+    /// A slice carried by the log entry itself, as written before slices were replicated out of the log.
+    /// Still applied for entries already in the log.
+    StoreData {
+        id: String,
+        value: Vec<u8>,
+        /// Unix timestamp in milliseconds, assigned by the leader accepting the write.
+        #[serde(default)]
+        written_at: u64,
+    },
+    /// Records a replicated slice. Its content is stored on a majority of the voters before the entry
+    /// is proposed, under the blob of `digest`; replicas without it fetch it before applying the entry,
+    /// and stop applying when no member can provide it.
+    StoreReference {
+        id: String,
+        /// Content digest, lower case.
+        digest: String,
+        size: u64,
+        written_at: u64,
+    },
+    /// Records an erasure coded slice. The shards are stored on their nodes before the entry is proposed,
+    /// the entry only carries where they are.
+    StoreErasureCoded {
+        id: String,
+        size: u64,
+        layout: ErasureLayout,
+        written_at: u64,
+    },
+    /// Removes slices on every replica, several ids can be deleted by one log entry.
+    DeleteData { ids: Vec<String> },
     ChangeNodeMap { },
 }
 
@@ -54,34 +112,90 @@ pub enum StorageNodeRequest {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StorageNodeResponse {
     pub value: Option<Vec<u8>>,
+    /// What applying the entry did, every replica comes to the same outcome.
+    pub outcome: ApplyOutcome,
 }
 
-#[derive(Debug)]
-pub struct StorageNodeStoreSnapshot {
-    pub meta: SnapshotMeta<StorageNodeId>,
+impl StorageNodeResponse {
+    fn applied(log_id: &LogId<StorageNodeId>) -> Self {
+        StorageNodeResponse { value: None, outcome: ApplyOutcome::Applied { index: log_id.index } }
+    }
 
-    /// The data of the state machine at the time of this snapshot.
-    pub data: Vec<u8>,
+    fn rejected(log_id: &LogId<StorageNodeId>, reason: RejectReason) -> Self {
+        StorageNodeResponse { value: None, outcome: ApplyOutcome::Rejected { index: log_id.index, reason } }
+    }
 }
 
-/**
- * Here defines a state machine of the raft, this state represents a copy of the data
- * between each node. Note that we are using `serde` to serialize the `data`, which has
- * a implementation to be serialized. Note that for this test we set both the key and
- * value as String, but you could set any type of value that has the serialization impl.
- */
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct StorageNodeStoreStateMachine {
-    pub last_applied_log: Option<LogId<StorageNodeId>>,
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ApplyOutcome {
+    /// The entry at log index `index` was applied to the state machine.
+    Applied { index: u64 },
+    /// The entry at log index `index` left the state machine as it was.
+    Rejected { index: u64, reason: RejectReason },
+}
 
-    // TODO: it should not be Option.
-    pub last_membership: EffectiveMembership<StorageNodeId>,
+/// Why an entry was rejected. Only decided from the entry itself, so that no replica applies
+/// an entry another one rejected. A replica failing to apply an entry for reasons of its own,
+/// e.g. its disk, stops applying instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RejectReason {
+    /// Not a slice id, see `digest_of_id`.
+    InvalidId { id: String },
+    /// The content, or the digest the entry gives for it, does not match the id.
+    ContentMismatch { id: String, digest: String },
+}
 
+/// Checks an entry against what every replica can tell from the entry alone.
+fn check_request(req: &StorageNodeRequest) -> Result<(), RejectReason> {
+    let invalid_id = |id: &String| RejectReason::InvalidId { id: id.clone() };
+    match req {
+        StorageNodeRequest::StoreData { id, value, .. } => {
+            let expected = content_digest(id).ok_or_else(|| invalid_id(id))?;
+            let digest = slice_digest(value);
+            if digest != expected {
+                return Err(RejectReason::ContentMismatch { id: id.clone(), digest });
+            }
+        }
+        StorageNodeRequest::StoreReference { id, digest, .. } => {
+            let expected = content_digest(id).ok_or_else(|| invalid_id(id))?;
+            if !digest.eq_ignore_ascii_case(&expected) {
+                return Err(RejectReason::ContentMismatch { id: id.clone(), digest: digest.clone() });
+            }
+        }
+        StorageNodeRequest::StoreErasureCoded { id, .. } => {
+            content_digest(id).ok_or_else(|| invalid_id(id))?;
+        }
+        StorageNodeRequest::DeleteData { ids } => {
+            if let Some(id) = ids.iter().find(|id| digest_of_id(id).is_none()) {
+                return Err(invalid_id(id));
+            }
+        }
+        StorageNodeRequest::ChangeNodeMap {} => {}
+    }
+    Ok(())
+}
 
-    /// Application data.
-    pub data: Vec<String>,
+/// Metadata the state machine keeps for every stored slice.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SliceMeta {
+    pub size: u64,
+    /// Index of the raft log entry that wrote the slice.
+    pub log_index: u64,
+    /// Unix timestamp in milliseconds, see `StorageNodeRequest::StoreData`.
+    pub written_at: u64,
+    /// Shards of an erasure coded slice, None for a slice replicated as a whole.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erasure: Option<ErasureLayout>,
+}
+
+#[derive(Debug)]
+pub struct StorageNodeStoreSnapshot {
+    pub meta: SnapshotMeta<StorageNodeId>,
 
-    pub nodemap_version: i64,
+    /// File holding the data of the state machine at the time of this snapshot.
+    pub path: PathBuf,
 }
 
 #[derive(Debug)]
@@ -105,52 +219,317 @@ pub struct StorageNodeFileStore {
     /// The current granted vote.
     //pub voted_for: RwLock<Option<Vote<StorageNodeId>>>,// alternatived by meta
 
-    pub snapshot_idx: Arc<Mutex<u64>>, //TODO: check this cache
+    /// The latest snapshot, reloaded from the snapshot directory on start.
+    pub current_snapshot: RwLock<Option<StorageNodeStoreSnapshot>>,
 
-    pub current_snapshot: RwLock<Option<StorageNodeStoreSnapshot>>,//TODO: check should cache or not
-}
+    /// File a snapshot sent by the leader is being spooled to.
+    pub receiving_snapshot: Mutex<Option<PathBuf>>,
 
-fn get_sled_db() -> Db {
-    sled::open(format!("{}/{}",ARGS.storage_location, "database")).unwrap()
-}
+    /// Where slice content is stored.
+    pub backend: Arc<dyn SliceBackend>,
 
+    /// Seals log entries, they carry slice content. None when encryption is not configured.
+    pub keyring: Option<Arc<Keyring>>,
 
-impl StorageNodeFileStore {
-    pub fn open_create(
-        open: Option<()>,
-        create: Option<()>,
-    ) -> StorageNodeFileStore {
-        tracing::info!("open: {:?}, create: {:?}", open, create);
+    /// Fenced when the log or a slice fails to be written because of the device.
+    pub health: Arc<NodeHealth>,
 
-        let db = get_sled_db();
+    /// Blobs and shards no longer referred to, deleted by the scrubber after a grace period.
+    pub collector: GarbageCollector,
+}
 
+pub fn get_sled_db() -> io::Result<Db> {
+    Ok(sled::open(format!("{}/{}",ARGS.storage_location, backend::directory::DATABASE_DIRECTORY))?)
+}
 
-        let log = db.open_tree(format!("trylog")).unwrap();
-        let meta = db.open_tree(format!("trymeta")).unwrap();
+/// Reading the raft log or the vote failed. Reported to openraft, which stops the raft.
+fn read_failure<E: std::error::Error + 'static>(subject: ErrorSubject<StorageNodeId>, err: E) -> StorageError<StorageNodeId> {
+    StorageIOError::new(subject, ErrorVerb::Read, AnyError::new(&err)).into()
+}
 
-        let current_snapshot = RwLock::new(None);
 
-        StorageNodeFileStore {
+impl StorageNodeFileStore {
+    pub fn open_create(
+        db: &Db,
+        backend: Arc<dyn SliceBackend>,
+        keyring: Option<Arc<Keyring>>,
+        health: Arc<NodeHealth>,
+    ) -> io::Result<StorageNodeFileStore> {
+        let log = db.open_tree(format!("trylog"))?;
+        let meta = db.open_tree(format!("trymeta"))?;
+        fs::create_dir_all(snapshot_directory())?;
+
+        let current_snapshot = load_latest_snapshot()?.map(|(meta, path)| {
+            tracing::info!("loaded snapshot {}", meta.snapshot_id);
+            StorageNodeStoreSnapshot { meta, path }
+        });
+        let mut state_machine = StorageNodeStoreStateMachine::open(db)?;
+        if let Some(snapshot_id) = state_machine.installing_snapshot()? {
+            // The node stopped while switching to a snapshot, whose file is kept until it is replaced.
+            tracing::warn!("installing snapshot {} again, it was interrupted", snapshot_id);
+            let (header, mut snapshot) = SnapshotReader::open(BufReader::new(fs::File::open(snapshot_path(&snapshot_id))?))?;
+            state_machine.replace(header, snapshot.entries(), &snapshot_id)?;
+        }
+        let state_machine = RwLock::new(state_machine);
+        let current_snapshot = RwLock::new(current_snapshot);
+
+        let store = StorageNodeFileStore {
             //last_purged_log_id: Default::default(),
             //id: raft_state_id,
             log,
             meta,
-            state_machine: Default::default(),
+            state_machine,
             //voted_for: Default::default(),
-            snapshot_idx: Arc::new(Mutex::new(0)),
             current_snapshot,
+            receiving_snapshot: Mutex::new(None),
+            backend,
+            keyring,
+            health,
+            collector: GarbageCollector::new(Duration::from_secs(ARGS.gc_grace_period)),
+        };
+        store.migrate_encoding()?;
+        Ok(store)
+    }
+
+    /// Rewrites the raft log, the vote and the metas written as JSON by earlier versions in the binary format.
+    /// Runs once, `LOG_FORMAT` records the format the trees are in.
+    fn migrate_encoding(&self) -> io::Result<()> {
+        if self.meta.get(LOG_FORMAT)?.as_deref() == Some(&[codec::FORMAT_VERSION][..]) {
+            return Ok(());
         }
+        let mut migrated = 0;
+        for record in self.log.iter() {
+            let (key, value) = record?;
+            let entry = self.decode_log_entry(&value)?;
+            self.log.insert(key, self.encode_log_entry(&entry)?)?;
+            migrated += 1;
+        }
+        for key in [&METAVOTE[..], &LAST_PURGED[..]] {
+            if let Some(value) = self.meta.get(key)? {
+                if codec::is_json(&value) {
+                    let value = if key == &METAVOTE[..] {
+                        codec::encode(&codec::decode::<Vote<StorageNodeId>>(&value)?)?
+                    } else {
+                        codec::encode(&codec::decode::<LogId<StorageNodeId>>(&value)?)?
+                    };
+                    self.meta.insert(key, value)?;
+                }
+            }
+        }
+        self.meta.insert(LOG_FORMAT, &[codec::FORMAT_VERSION][..])?;
+        self.log.flush()?;
+        self.meta.flush()?;
+        if migrated > 0 {
+            tracing::info!("rewrote {} raft log entries in binary format version {}", migrated, codec::FORMAT_VERSION);
+        }
+        Ok(())
     }
 
-    fn get_last_purged_log_id(&self) -> Option<LogId<StorageNodeId>>{
-        match self.meta.get(b"last-purged").unwrap() {
-            None => None,
-            Some(res) => serde_json::from_slice::<Option<LogId<StorageNodeId>>>(&*res).unwrap()
+    fn encode_log_entry(&self, entry: &Entry<StorageRaftTypeConfig>) -> io::Result<IVec> {
+        let data = codec::encode(entry)?;
+        match &self.keyring {
+            Some(keyring) => Ok(IVec::from(keyring.seal(&data)?)),
+            None => Ok(IVec::from(data)),
+        }
+    }
+
+    /// Entries appended before encryption was enabled are not sealed and read as they are,
+    /// those appended before the binary format are JSON.
+    fn decode_log_entry(&self, data: &[u8]) -> io::Result<Entry<StorageRaftTypeConfig>> {
+        if Keyring::is_sealed(data) {
+            let keyring = self.keyring.as_ref().ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raft log is encrypted but no master key file is configured",
+            ))?;
+            return codec::decode(&keyring.open(data)?);
+        }
+        codec::decode(data)
+    }
+
+    /// Drops the reference slice `id` held on its blob, leaving the blob to the collector with the last reference,
+    /// and deletes any copy stored under `id` itself before deduplication.
+    fn release_slice(&self, sm: &StorageNodeStoreStateMachine, id: &str, previous: Option<&SliceMeta>) -> io::Result<()> {
+        if previous.map_or(false, |meta| meta.erasure.is_none()) && sm.drop_reference(id)? {
+            if let Some(blob) = blob_id(id) {
+                self.collector.mark(&blob);
+            }
+        }
+        if !is_blob_id(id) {
+            if let Err(err) = self.backend.delete(id) {
+                tracing::error!("failed to delete slice {}: {}", id, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves the shards this node holds for an erasure coded slice that is deleted or overwritten to the collector,
+    /// except those also part of `keep`.
+    fn release_local_shards(&self, meta: Option<&SliceMeta>, keep: Option<&ErasureLayout>) {
+        let layout = match meta.and_then(|meta| meta.erasure.as_ref()) {
+            Some(layout) => layout,
+            None => return,
+        };
+        let kept = |id: &str| keep.map_or(false, |keep| keep.local_shards(ARGS.node_id).any(|shard| shard.id == id));
+        for shard in layout.local_shards(ARGS.node_id).filter(|shard| !kept(&shard.id)) {
+            self.collector.mark(&shard.id);
+        }
+    }
+
+    /// Stores the slice files carried by a snapshot of an earlier version that are not already here.
+    /// If one fails, those stored so far are removed again and the snapshot is not installed.
+    fn install_snapshot_files<R: io::Read>(&self, files: &mut SnapshotReader<R>) -> io::Result<()> {
+        let mut stored = Vec::new();
+        let result: io::Result<()> = (|| {
+            while let Some((id, body)) = files.next_file()? {
+                if self.backend.stat(&id).is_ok() {
+                    continue;
+                }
+                self.backend.put_verified(&id, &body)?;
+                stored.push(id);
+            }
+            Ok(())
+        })();
+        if let Err(err) = &result {
+            tracing::error!("failed to store the slices of a snapshot: {}", err);
+            for id in stored {
+                if let Err(err) = self.backend.delete(&id) {
+                    tracing::error!("failed to delete slice {} of an aborted snapshot: {}", id, err);
+                }
+            }
+        }
+        result
+    }
+
+    /// Blobs of the replicated slices among snapshot `entries` this node holds no content of, neither as a blob
+    /// nor, for slices written before deduplication, under the slice id.
+    fn missing_blobs(&self, entries: impl Iterator<Item = io::Result<SnapshotEntry>>) -> io::Result<BTreeSet<String>> {
+        let mut missing = BTreeSet::new();
+        for entry in entries {
+            let id = match entry? {
+                SnapshotEntry::Slice(id, meta) if meta.erasure.is_none() => id,
+                _ => continue,
+            };
+            let blob = match blob_id(&id) {
+                Some(blob) => blob,
+                None => continue,
+            };
+            if missing.contains(&blob) {
+                continue;
+            }
+            // Referred to once the snapshot is installed, if it was garbage here.
+            self.collector.keep(&blob);
+            let mut held = false;
+            for stored in [&blob, &id] {
+                match self.backend.stat(stored) {
+                    Ok(_) => {
+                        held = true;
+                        break;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if !held {
+                missing.insert(blob);
+            }
         }
+        Ok(missing)
     }
 
-    fn set_last_purged_log_id(&self, id: &LogId<StorageNodeId>) {
-        self.meta.insert(b"last-purged", IVec::from(serde_json::to_vec(id).unwrap()));
+    /// Releases what `state_machine` holds and snapshot file `path` does not. The snapshot may cover deletions
+    /// whose log entries this node never saw, their tombstones may be purged already: the slices it does not list go,
+    /// with their local shards, and the blobs it does not refer to are left to the collector.
+    /// The trees and the snapshot are in the same order, they are walked side by side.
+    fn release_replaced(&self, state_machine: &StorageNodeStoreStateMachine, path: &Path) -> io::Result<()> {
+        let (_, mut snapshot) = SnapshotReader::open(BufReader::new(fs::File::open(path)?))?;
+        for entry in state_machine.slices() {
+            let (id, previous) = entry?;
+            if snapshot.holds(EntryKind::Slice, &id)? {
+                continue;
+            }
+            if let Err(err) = self.backend.delete(&id) {
+                tracing::error!("failed to delete slice {} deleted by the snapshot: {}", id, err);
+            }
+            self.release_local_shards(Some(&previous), None);
+        }
+        for entry in state_machine.references() {
+            let (digest, _) = entry?;
+            if !snapshot.holds(EntryKind::Reference, &digest)? {
+                self.collector.mark(&blob_id_of_digest(&digest));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `snapshot` the current snapshot and removes the file of the one it replaces.
+    async fn set_current_snapshot(&self, snapshot: StorageNodeStoreSnapshot) {
+        let path = snapshot.path.clone();
+        let previous = self.current_snapshot.write().await.replace(snapshot);
+        if let Some(previous) = previous.filter(|previous| previous.path != path) {
+            if let Err(err) = remove_snapshot(&previous.meta.snapshot_id) {
+                tracing::warn!("failed to remove snapshot {}: {}", previous.meta.snapshot_id, err);
+            }
+        }
+    }
+
+    /// Numbers snapshots, built and received. Kept in sled so that snapshot ids stay unique across restarts.
+    fn next_snapshot_idx(&self) -> io::Result<u64> {
+        let idx = self.meta.update_and_fetch(b"snapshot-idx", |previous| {
+            let previous = previous.and_then(|bytes| bytes.try_into().ok()).map_or(0, u64::from_be_bytes);
+            Some((previous + 1).to_be_bytes().to_vec())
+        })?;
+        Ok(idx.and_then(|bytes| (&*bytes).try_into().ok()).map_or(0, u64::from_be_bytes))
+    }
+
+    /// Tells whether this node left the voters because its storage went read-only while it led.
+    pub fn stepped_down(&self) -> io::Result<bool> {
+        Ok(self.meta.contains_key(STEPPED_DOWN)?)
+    }
+
+    /// Records whether this node left the voters, kept in sled so that it asks to rejoin them after a restart too.
+    pub fn set_stepped_down(&self, stepped_down: bool) -> io::Result<()> {
+        if stepped_down {
+            self.meta.insert(STEPPED_DOWN, &[1u8][..])?;
+        } else {
+            self.meta.remove(STEPPED_DOWN)?;
+        }
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// Reads the log entry stored under `key`, a record that does not decode is reported with its index.
+    fn read_log_record(&self, key: &[u8], value: &[u8]) -> Result<Entry<StorageRaftTypeConfig>, StorageError<StorageNodeId>> {
+        self.decode_log_entry(value).map_err(|source| {
+            let index = key.try_into().map_or(0, u64::from_be_bytes);
+            read_failure(ErrorSubject::Logs, NodeError::CorruptLogRecord { index, source })
+        })
+    }
+
+    /// A write to the raft log that did not happen leaves the node unable to take part in the group,
+    /// it is fenced and openraft shuts the raft down on the returned error.
+    fn log_write_failure(&self, subject: ErrorSubject<StorageNodeId>, err: impl Into<io::Error>) -> StorageError<StorageNodeId> {
+        let err = err.into();
+        self.health.fence(format!("failed to write raft log: {}", err));
+        StorageIOError::new(subject, ErrorVerb::Write, AnyError::new(&err)).into()
+    }
+
+    /// Same for the state machine: an entry that could not be applied is not applied on this node.
+    fn state_machine_write_failure(&self, err: io::Error) -> StorageError<StorageNodeId> {
+        self.health.fence(format!("failed to write state machine: {}", err));
+        StorageIOError::new(ErrorSubject::StateMachine, ErrorVerb::Write, AnyError::new(&err)).into()
+    }
+
+    fn get_last_purged_log_id(&self) -> Result<Option<LogId<StorageNodeId>>, StorageError<StorageNodeId>> {
+        match self.meta.get(LAST_PURGED).map_err(|e| read_failure(ErrorSubject::Logs, io::Error::from(e)))? {
+            None => Ok(None),
+            Some(res) => codec::decode::<LogId<StorageNodeId>>(&*res).map(Some).map_err(|e| read_failure(ErrorSubject::Logs, e)),
+        }
+    }
+
+    fn set_last_purged_log_id(&self, id: &LogId<StorageNodeId>) -> Result<(), StorageError<StorageNodeId>> {
+        let value = codec::encode(id).map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
+        self.meta.insert(LAST_PURGED, value).map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
+        Ok(())
     }
 }
 
@@ -158,13 +537,15 @@ impl StorageNodeFileStore {
 impl RaftLogReader<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
     async fn get_log_state(&mut self) -> Result<LogState<StorageRaftTypeConfig>, StorageError<StorageNodeId>> {
         let log = &self.log;
-        let last = log.iter()
-                      .rev()
-                      .next()
-                      .map(|res| res.unwrap()).map(|(_, val)|
-            serde_json::from_slice::<Entry<StorageRaftTypeConfig>>(&*val).unwrap().log_id);
+        let last = match log.iter().next_back() {
+            Some(record) => {
+                let (key, val) = record.map_err(|e| read_failure(ErrorSubject::Logs, io::Error::from(e)))?;
+                Some(self.read_log_record(&key, &val)?.log_id)
+            }
+            None => None,
+        };
 
-        let last_purged = self.get_last_purged_log_id();
+        let last_purged = self.get_last_purged_log_id()?;
 
         let last = match last {
             None => last_purged,
@@ -183,13 +564,12 @@ impl RaftLogReader<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
         range: RB,
     ) -> Result<Vec<Entry<StorageRaftTypeConfig>>, StorageError<StorageNodeId>> {
         let log = &self.log;
-        let response = log.range(transform_range_bound(range))
-                          .map(|res| res.unwrap())
-                          .map(|(_, val)|
-                              serde_json::from_slice::<Entry<StorageRaftTypeConfig>>(&*val).unwrap())
-                          .collect();
-
-        Ok(response)
+        log.range(transform_range_bound(range))
+           .map(|record| {
+               let (key, val) = record.map_err(|e| read_failure(ErrorSubject::Logs, io::Error::from(e)))?;
+               self.read_log_record(&key, &val)
+           })
+           .collect()
     }
 }
 
@@ -209,35 +589,22 @@ fn serialize_bound(
 }
 
 #[async_trait]
-impl RaftSnapshotBuilder<StorageRaftTypeConfig, Cursor<Vec<u8>>> for Arc<StorageNodeFileStore> {
+impl RaftSnapshotBuilder<StorageRaftTypeConfig, File> for Arc<StorageNodeFileStore> {
     #[tracing::instrument(level = "trace", skip(self))]
     async fn build_snapshot(
         &mut self,
-    ) -> Result<Snapshot<StorageNodeId, Cursor<Vec<u8>>>, StorageError<StorageNodeId>> {
-        let (data, last_applied_log, last_membership);
-
-        {
-            // Serialize the data of the state machine.
-            let state_machine = self.state_machine.read().await;
-            data = serde_json::to_vec(&*state_machine)
-                .map_err(|e| StorageIOError::new(ErrorSubject::StateMachine, ErrorVerb::Read, AnyError::new(&e)))?;
-
-            last_applied_log = state_machine.last_applied_log;
-            last_membership = state_machine.last_membership.clone();
-        }
-
-        let last_applied_log = match last_applied_log {
+    ) -> Result<Snapshot<StorageNodeId, File>, StorageError<StorageNodeId>> {
+        let state_machine = self.state_machine.read().await;
+        let last_applied_log = match state_machine.last_applied_log {
+            Some(log_id) => log_id,
             None => {
-                panic!("can not compact empty state machine");
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "can not compact empty state machine");
+                return Err(StorageIOError::new(ErrorSubject::StateMachine, ErrorVerb::Read, AnyError::new(&err)).into());
             }
-            Some(x) => x,
         };
 
-        let snapshot_idx = {
-            let mut l = self.snapshot_idx.lock().unwrap();
-            *l += 1;
-            *l
-        };
+        let snapshot_idx = self.next_snapshot_idx()
+            .map_err(|e| StorageIOError::new(ErrorSubject::StateMachine, ErrorVerb::Write, AnyError::new(&e)))?;
 
         let snapshot_id = format!(
             "{}-{}-{}",
@@ -246,46 +613,70 @@ impl RaftSnapshotBuilder<StorageRaftTypeConfig, Cursor<Vec<u8>>> for Arc<Storage
 
         let meta = SnapshotMeta {
             last_log_id: last_applied_log,
-            last_membership ,
+            last_membership: state_machine.last_membership.clone(),
             snapshot_id,
         };
 
-        let snapshot = StorageNodeStoreSnapshot {
+        // Slices are referred to by id, a node installing the snapshot fetches the content it lacks.
+        let path = snapshot_path(&meta.snapshot_id);
+        let snapshot_write_failure = |e: io::Error| StorageIOError::new(
+            ErrorSubject::Snapshot(meta.clone()),
+            ErrorVerb::Write,
+            AnyError::new(&e),
+        );
+        // The trees are streamed into the file entry by entry, off the executor. The guard is held meanwhile,
+        // applies wait and the snapshot is consistent.
+        let reading = state_machine.clone();
+        let (spool_path, spool_meta) = (path.clone(), meta.clone());
+        run_blocking(move || {
+            let partial = partial_path(&spool_path);
+            let mut out = BufWriter::new(fs::File::create(&partial)?);
+            write_snapshot(&mut out, &reading.header(), reading.entries())?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&partial, &spool_path)?;
+            save_snapshot_meta(&spool_meta)
+        }).await.map_err(snapshot_write_failure)?;
+        drop(state_machine);
+
+        let data = File::open(&path).await.map_err(snapshot_write_failure)?;
+        self.set_current_snapshot(StorageNodeStoreSnapshot {
             meta: meta.clone(),
-            data: data.clone(),
-        };
-
-        {
-            let mut current_snapshot = self.current_snapshot.write().await;
-            *current_snapshot = Some(snapshot);
-        }
+            path,
+        }).await;
 
         Ok(Snapshot {
             meta,
-            snapshot: Box::new(Cursor::new(data)),
+            snapshot: Box::new(data),
         })
     }
 }
 
 const METAVOTE: &'static [u8; 9] = b"meta-vote";
+const LAST_PURGED: &'static [u8; 11] = b"last-purged";
+/// Format version of the records of the log and meta trees, see `codec`.
+const LOG_FORMAT: &'static [u8; 10] = b"log-format";
+/// Set while this node is a learner because it stepped down, see `network::management::step_down`.
+const STEPPED_DOWN: &'static [u8; 12] = b"stepped-down";
 
 #[async_trait]
 impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
-    type SnapshotData = Cursor<Vec<u8>>;
+    type SnapshotData = File;
     type LogReader = Self;
     type SnapshotBuilder = Self;
 
     #[tracing::instrument(level = "trace", skip(self))]
     async fn save_vote(&mut self, vote: &Vote<StorageNodeId>) -> Result<(), StorageError<StorageNodeId>> {
-        let value = IVec::from(serde_json::to_vec(vote).unwrap());
-        self.meta.insert(METAVOTE, value);
+        let value = codec::encode(vote).map_err(|e| self.log_write_failure(ErrorSubject::Vote, e))?;
+        self.meta.insert(METAVOTE, value).map_err(|e| self.log_write_failure(ErrorSubject::Vote, e))?;
+        // A vote forgotten in a crash could be granted twice in the same term.
+        self.meta.flush_async().await.map_err(|e| self.log_write_failure(ErrorSubject::Vote, e))?;
         Ok(())
     }
 
     async fn read_vote(&mut self) -> Result<Option<Vote<StorageNodeId>>, StorageError<StorageNodeId>> {
-        match self.meta.get(METAVOTE).unwrap() {
+        match self.meta.get(METAVOTE).map_err(|e| read_failure(ErrorSubject::Vote, io::Error::from(e)))? {
             Some(res) => {
-                Ok(Some(serde_json::from_slice::<Vote<StorageNodeId>>(&*res).unwrap()))
+                Ok(Some(codec::decode::<Vote<StorageNodeId>>(&*res).map_err(|e| read_failure(ErrorSubject::Vote, e))?))
             }
             None => Ok(None)
         }
@@ -302,9 +693,11 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
     ) -> Result<(), StorageError<StorageNodeId>> {
         let log = &self.log;
         for entry in entries {
-            log.insert(entry.log_id.index.to_be_bytes(), IVec::from(serde_json::to_vec(&*entry).unwrap()));
+            let value = self.encode_log_entry(entry).map_err(|e| self.log_write_failure(ErrorSubject::Log(entry.log_id), e))?;
+            log.insert(entry.log_id.index.to_be_bytes(), value)
+               .map_err(|e| self.log_write_failure(ErrorSubject::Log(entry.log_id), e))?;
         }
-        log.flush_async().await.expect("append_to_log: Flush failed");
+        log.flush_async().await.map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
         Ok(())
     }
 
@@ -316,13 +709,11 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
         tracing::debug!("delete_log: [{:?}, +oo)", log_id);
 
         let log = &self.log;
-        let keys = log.range(transform_range_bound(log_id.index..))
-                      .map(|res| res.unwrap())
-                      .map(|(k, _v)| k); //TODO Why originally used collect instead of the iter.
-        for key in keys {
-            log.remove(&key);
+        for record in log.range(transform_range_bound(log_id.index..)) {
+            let (key, _) = record.map_err(|e| read_failure(ErrorSubject::Logs, io::Error::from(e)))?;
+            log.remove(&key).map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
         }
-        log.flush_async().await.expect("delete_conflict_logs_since: Flush failed");
+        log.flush_async().await.map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
         Ok(())
     }
 
@@ -330,22 +721,29 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
     async fn purge_logs_upto(&mut self, log_id: LogId<StorageNodeId>) -> Result<(), StorageError<StorageNodeId>> {
         tracing::debug!("delete_log: [{:?}, +oo)", log_id);
 
-        {
-            let mut ld = self.get_last_purged_log_id();
-            assert!(ld <= Some(log_id));
-            ld = Some(log_id);
-            self.set_last_purged_log_id(&ld.unwrap());
+        let last_purged = self.get_last_purged_log_id()?;
+        if last_purged > Some(log_id) {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("purging logs up to {} after they were purged up to {:?}", log_id, last_purged),
+            );
+            return Err(StorageIOError::new(ErrorSubject::Logs, ErrorVerb::Delete, AnyError::new(&err)).into());
         }
+        self.set_last_purged_log_id(&log_id)?;
 
-        {
-            let log = &self.log;
-
-            let keys = log.range(transform_range_bound(..=log_id.index))
-                          .map(|res| res.unwrap())
-                          .map(|(k, _)| k);
-            for key in keys {
-                log.remove(&key);
-            }
+        let log = &self.log;
+        for record in log.range(transform_range_bound(..=log_id.index)) {
+            let (key, _) = record.map_err(|e| read_failure(ErrorSubject::Logs, io::Error::from(e)))?;
+            log.remove(&key).map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
+        }
+        log.flush_async().await.map_err(|e| self.log_write_failure(ErrorSubject::Logs, e))?;
+
+        // Logs are purged once covered by a snapshot: a node that has not applied these deletions
+        // gets the snapshot and drops the slices it no longer lists, it does not need their tombstones.
+        match self.state_machine.read().await.purge_tombstones(log_id.index) {
+            Ok(purged) if purged > 0 => tracing::debug!("purged {} tombstones up to log index {}", purged, log_id.index),
+            Ok(_) => {}
+            Err(err) => tracing::warn!("failed to purge tombstones up to log index {}: {}", log_id.index, err),
         }
 
         Ok(())
@@ -365,33 +763,131 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
     ) -> Result<Vec<StorageNodeResponse>, StorageError<StorageNodeId>> {
         let mut res = Vec::with_capacity(entries.len());
 
+        // Content of replicated slices travels out of the log, replicas that were not sent it
+        // fetch it before applying, from the leader that proposed the latest entries first.
+        let blobs: BTreeSet<_> = entries
+            .iter()
+            .filter_map(|entry| match &entry.payload {
+                EntryPayload::Normal(req @ StorageNodeRequest::StoreReference { digest, .. }) if check_request(req).is_ok() => {
+                    Some(blob_id_of_digest(digest))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut missing = HashMap::new();
+        if let Some(last) = entries.last().filter(|_| !blobs.is_empty()) {
+            let peers = members(self, Some(last.log_id.leader_id.node_id)).await;
+            missing = ensure_blobs(self, blobs.into_iter().collect(), peers).await;
+        }
+
         let mut sm = self.state_machine.write().await;
+        let failed = |err| self.state_machine_write_failure(err);
 
         for entry in entries {
             tracing::debug!(%entry.log_id, "replicate to sm");
 
             sm.last_applied_log = Some(entry.log_id);
 
+            if let EntryPayload::Normal(req) = &entry.payload {
+                if let Err(reason) = check_request(req) {
+                    tracing::warn!(%entry.log_id, "entry rejected: {:?}", reason);
+                    res.push(StorageNodeResponse::rejected(&entry.log_id, reason));
+                    continue;
+                }
+            }
+
             match entry.payload {
-                EntryPayload::Blank => res.push(StorageNodeResponse { value: None }),
+                EntryPayload::Blank => res.push(StorageNodeResponse::applied(&entry.log_id)),
                 EntryPayload::Normal(ref req) => match req {
-                    StorageNodeRequest::StoreData { id: key, value } => {
-                        sm.data.push(key.clone());
-                        if let Err(_) = fs_io::store_slice(key, value) {//TODO: return error when can't storage.
-                        } else {
-                            res.push(StorageNodeResponse { value: None })
+                    StorageNodeRequest::StoreData { id: key, value, written_at } => {
+                        // Content already stored for another slice is not written again. It is stored before
+                        // the slice is recorded: a replica that cannot store it stops applying rather than
+                        // record a slice it does not hold.
+                        let blob = blob_id(key).unwrap_or_else(|| key.clone());
+                        self.collector.keep(&blob);
+                        if let Err(err) = self.backend.stat(&blob) {
+                            if err.kind() != io::ErrorKind::NotFound {
+                                return Err(failed(err));
+                            }
+                            if let Err(err) = self.backend.put_verified(&blob, value) {
+                                tracing::error!(%entry.log_id, "failed to store slice {}: {}", key, err);
+                                self.health.report_write_failure(&format!("failed to store slice {}", key), &err);
+                                return Err(failed(err));
+                            }
+                        }
+                        sm.remove_tombstone(key).map_err(failed)?;
+                        let previous = sm.insert_slice(key, &SliceMeta {
+                            size: value.len() as u64,
+                            log_index: entry.log_id.index,
+                            written_at: *written_at,
+                            erasure: None,
+                        }).map_err(failed)?;
+                        if previous.as_ref().map_or(true, |meta| meta.erasure.is_some()) {
+                            sm.add_reference(key).map_err(failed)?;
+                        }
+                        self.release_local_shards(previous.as_ref(), None);
+                        res.push(StorageNodeResponse::applied(&entry.log_id))
+                    },
+                    StorageNodeRequest::StoreReference { id, digest, size, written_at } => {
+                        // A replica no member can give the content to stops applying rather than record a slice
+                        // it does not hold. Members that cannot be reached were asked again until they answered.
+                        if let Some(err) = missing.remove(&blob_id_of_digest(digest)) {
+                            tracing::error!(%entry.log_id, "content of slice {} is missing: {}", id, err);
+                            return Err(failed(err));
+                        }
+                        sm.remove_tombstone(id).map_err(failed)?;
+                        let previous = sm.insert_slice(id, &SliceMeta {
+                            size: *size,
+                            log_index: entry.log_id.index,
+                            written_at: *written_at,
+                            erasure: None,
+                        }).map_err(failed)?;
+                        if previous.as_ref().map_or(true, |meta| meta.erasure.is_some()) {
+                            sm.add_reference(id).map_err(failed)?;
                         }
+                        self.release_local_shards(previous.as_ref(), None);
+                        res.push(StorageNodeResponse::applied(&entry.log_id))
+                    },
+                    StorageNodeRequest::StoreErasureCoded { id, size, layout, written_at } => {
+                        sm.remove_tombstone(id).map_err(failed)?;
+                        let previous = sm.insert_slice(id, &SliceMeta {
+                            size: *size,
+                            log_index: entry.log_id.index,
+                            written_at: *written_at,
+                            erasure: Some(layout.clone()),
+                        }).map_err(failed)?;
+                        // Shards of this layout were pushed already, only those of a previous version go.
+                        for shard in layout.local_shards(ARGS.node_id) {
+                            self.collector.keep(&shard.id);
+                        }
+                        self.release_local_shards(previous.as_ref(), Some(layout));
+                        // The content of an earlier replicated write of the same id.
+                        self.release_slice(&sm, id, previous.as_ref()).map_err(failed)?;
+                        res.push(StorageNodeResponse::applied(&entry.log_id))
+                    },
+                    StorageNodeRequest::DeleteData { ids } => {
+                        for id in ids {
+                            let previous = sm.remove_slice(id).map_err(failed)?;
+                            self.release_local_shards(previous.as_ref(), None);
+                            self.release_slice(&sm, id, previous.as_ref()).map_err(failed)?;
+                            if previous.is_some() {
+                                sm.tombstone(id, entry.log_id.index).map_err(failed)?;
+                            }
+                        }
+                        res.push(StorageNodeResponse::applied(&entry.log_id))
                     },
                     StorageNodeRequest::ChangeNodeMap {} => {
-                        sm.nodemap_version += 1
+                        sm.nodemap_version += 1;
+                        res.push(StorageNodeResponse::applied(&entry.log_id))
                     }
                 },
                 EntryPayload::Membership(ref mem) => {
                     sm.last_membership = EffectiveMembership::new(Some(entry.log_id), mem.clone());
-                    res.push(StorageNodeResponse { value: None })
+                    res.push(StorageNodeResponse::applied(&entry.log_id))
                 }
             };
         }
+        sm.save_applied().map_err(failed)?;
         Ok(res)
     }
 
@@ -401,7 +897,15 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
 
     #[tracing::instrument(level = "trace", skip(self))]
     async fn begin_receiving_snapshot(&mut self) -> Result<Box<Self::SnapshotData>, StorageError<StorageNodeId>> {
-        Ok(Box::new(Cursor::new(Vec::new())))
+        let spool_failure = |e: io::Error| StorageIOError::new(ErrorSubject::StateMachine, ErrorVerb::Write, AnyError::new(&e));
+        let snapshot_idx = self.next_snapshot_idx().map_err(spool_failure)?;
+        let path = snapshot_path(&format!("receiving-{}", snapshot_idx));
+        let data = File::create(&path).await.map_err(spool_failure)?;
+        // A snapshot abandoned by a leader that went away is not going to be installed.
+        if let Some(abandoned) = self.receiving_snapshot.lock().unwrap().replace(path) {
+            let _ = fs::remove_file(abandoned);
+        }
+        Ok(Box::new(data))
     }
 
     #[tracing::instrument(level = "trace", skip(self, snapshot))]
@@ -411,33 +915,71 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
         snapshot: Box<Self::SnapshotData>,
     ) -> Result<StateMachineChanges<StorageRaftTypeConfig>, StorageError<StorageNodeId>> {
         //panic!("Starting installing snapshot.");
-        tracing::info!(
-            { snapshot_size = snapshot.get_ref().len() },
-            "decoding snapshot for installation"
+        let snapshot_read_failure = |e: io::Error| StorageIOError::new(
+            ErrorSubject::Snapshot(meta.clone()),
+            ErrorVerb::Read,
+            AnyError::new(&e),
         );
+        // Kept as the current snapshot and replayed from on start, it must be the spooled file.
+        let not_received = || io::Error::new(io::ErrorKind::NotFound, format!("snapshot {} was not received", meta.snapshot_id));
+        if self.receiving_snapshot.lock().unwrap().is_none() {
+            return Err(snapshot_read_failure(not_received()).into());
+        }
+        let mut data = snapshot.into_std().await;
+        let store = Arc::clone(self);
+        // Reading and checking the snapshot against the backend is file system work, done off the executor.
+        let (header, missing) = run_blocking(move || {
+            data.sync_all()?;
+            tracing::info!({ snapshot_size = data.metadata()?.len() }, "decoding snapshot for installation");
+            data.seek(SeekFrom::Start(0))?;
+            let (header, mut snapshot) = SnapshotReader::open(BufReader::new(data))?;
+            store.install_snapshot_files(&mut snapshot)?;
+            let missing = store.missing_blobs(snapshot.entries())?;
+            Ok((header, missing))
+        }).await.map_err(snapshot_read_failure)?;
+
+        // The content of the slices goes first, the state machine only refers to it once it is all here.
+        // This node may not know the group yet, the snapshot tells who its members are.
+        let peers = member_addrs(&header.last_membership.membership, Some(meta.last_log_id.leader_id.node_id));
+        let missing = ensure_blobs(self, missing.into_iter().collect(), peers).await;
+        if let Some((blob, err)) = missing.into_iter().next() {
+            tracing::error!("snapshot {} is not installed, content of blob {} is missing: {}", meta.snapshot_id, blob, err);
+            return Err(snapshot_read_failure(err).into());
+        }
 
-        let new_snapshot = StorageNodeStoreSnapshot {
-            meta: meta.clone(),
-            data: snapshot.into_inner(),
-        };
+        // Keep the spooled file as the current snapshot, under its id. It is installed again
+        // from there if the node stops before the state machine is switched.
+        let path = snapshot_path(&meta.snapshot_id);
+        let received = self.receiving_snapshot.lock().unwrap().take().ok_or_else(not_received).map_err(snapshot_read_failure)?;
+        let (kept_path, kept_meta) = (path.clone(), meta.clone());
+        run_blocking(move || {
+            fs::rename(&received, &kept_path)?;
+            save_snapshot_meta(&kept_meta)
+        }).await.map_err(snapshot_read_failure)?;
 
-        // Update the state machine.
         {
-            let updated_state_machine: StorageNodeStoreStateMachine =
-                serde_json::from_slice(&new_snapshot.data).map_err(|e| {
-                    StorageIOError::new(
-                        ErrorSubject::Snapshot(new_snapshot.meta.clone()),
-                        ErrorVerb::Read,
-                        AnyError::new(&e),
-                    )
-                })?;
             let mut state_machine = self.state_machine.write().await;
-            *state_machine = updated_state_machine;
+            let store = Arc::clone(self);
+            let mut replacing = state_machine.clone();
+            let snapshot_id = meta.snapshot_id.clone();
+            let installed_path = path.clone();
+            let replaced = run_blocking(move || {
+                store.release_replaced(&replacing, &installed_path)?;
+                // Read again from the file, the state machine is installed entry by entry as well.
+                let (header, mut snapshot) = SnapshotReader::open(BufReader::new(fs::File::open(&installed_path)?))?;
+                replacing.replace(header, snapshot.entries(), &snapshot_id)?;
+                Ok(replacing)
+            }).await.map_err(|err| self.state_machine_write_failure(err))?;
+            *state_machine = replaced;
         }
 
+        let new_snapshot = StorageNodeStoreSnapshot {
+            meta: meta.clone(),
+            path,
+        };
+
         // Update current snapshot.
-        let mut current_snapshot = self.current_snapshot.write().await;
-        *current_snapshot = Some(new_snapshot);
+        self.set_current_snapshot(new_snapshot).await;
         Ok(StateMachineChanges {
             last_applied: meta.last_log_id,
             is_snapshot: true,
@@ -451,10 +993,14 @@ impl RaftStorage<StorageRaftTypeConfig> for Arc<StorageNodeFileStore> {
         //panic!("Starting getting snapshot.");
         match &*self.current_snapshot.read().await {
             Some(snapshot) => {
-                let data = snapshot.data.clone();
+                let data = File::open(&snapshot.path).await.map_err(|e| StorageIOError::new(
+                    ErrorSubject::Snapshot(snapshot.meta.clone()),
+                    ErrorVerb::Read,
+                    AnyError::new(&e),
+                ))?;
                 Ok(Some(Snapshot {
                     meta: snapshot.meta.clone(),
-                    snapshot: Box::new(Cursor::new(data)),
+                    snapshot: Box::new(data),
                 }))
             }
             None => Ok(None),
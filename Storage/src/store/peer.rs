@@ -0,0 +1,65 @@
+use std::io;
+use std::lazy::SyncLazy;
+use std::time::Duration;
+
+use crate::store::digest::verify_slice;
+
+/// A member that does not accept a connection within this long is taken as down.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shared by raft RPCs and the transfers of blobs, shards and slices between members, so that connections are reused.
+static CLIENT: SyncLazy<reqwest::Client> = SyncLazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .expect("http client")
+});
+
+pub fn client() -> &'static reqwest::Client {
+    &CLIENT
+}
+
+/// Url of `id` under `/<kind>/` on the member at `addr`, `kind` being "blob", "shard" or "slice".
+pub fn peer_url(addr: &str, kind: &str, id: &str) -> io::Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(&format!("http://{}/{}/", addr, kind))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid peer address {}: {}", addr, e)))?;
+    url.path_segments_mut().unwrap().pop_if_empty().push(id);
+    Ok(url)
+}
+
+pub fn request_error(err: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// Reads `id` from the member at `addr`, checked against its id. Fails with `NotFound` when the member
+/// does not hold it and with `InvalidData` when what it sent does not match it.
+pub async fn fetch(addr: &str, kind: &str, id: &str) -> io::Result<Vec<u8>> {
+    let resp = client().get(peer_url(addr, kind, id)?).send().await.map_err(request_error)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not hold {} {}", addr, kind, id)));
+    }
+    if !resp.status().is_success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} answered {}", addr, resp.status())));
+    }
+    let body = resp.bytes().await.map_err(request_error)?.to_vec();
+    verify_slice(id, &body)?;
+    Ok(body)
+}
+
+/// Stores `id` on the member at `addr`.
+pub async fn store(addr: &str, kind: &str, id: &str, body: Vec<u8>) -> io::Result<()> {
+    let resp = client().put(peer_url(addr, kind, id)?).body(body).send().await.map_err(request_error)?;
+    if !resp.status().is_success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("storing {} {} on {} failed: {}", kind, id, addr, resp.status())));
+    }
+    Ok(())
+}
+
+/// Sleeps long enough for `bytes` to keep a transfer under `bytes_per_second`, 0 meaning unlimited.
+pub async fn throttle(bytes: usize, bytes_per_second: u64) {
+    if bytes_per_second == 0 {
+        return;
+    }
+    let secs = bytes as f64 / bytes_per_second as f64;
+    tokio::time::sleep(Duration::from_secs_f64(secs)).await;
+}
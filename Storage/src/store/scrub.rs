@@ -0,0 +1,332 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::ARGS;
+use crate::store::backend::multi_disk::is_on_offline_disk;
+use crate::store::dedup::{blob_id, is_blob_id};
+use crate::store::digest::is_corrupted;
+use crate::store::erasure::{parse_shard_id, rebuild_local_shards};
+use crate::store::peer::{self, throttle};
+use crate::store::StorageNodeFileStore;
+
+/// Number of ids taken from the backend listing at a time.
+const LIST_BATCH: usize = 1024;
+
+/// Counters describing the scrubber's progress and findings, exposed by `/node-metrics`.
+#[derive(Debug, Default)]
+pub struct ScrubStats {
+    passes_completed: AtomicU64,
+    slices_scanned: AtomicU64,
+    bytes_scanned: AtomicU64,
+    corrupted_found: AtomicU64,
+    lost_with_disk: AtomicU64,
+    missing_found: AtomicU64,
+    repaired: AtomicU64,
+    repair_failed: AtomicU64,
+    shards_rebuilt: AtomicU64,
+    garbage_collected: AtomicU64,
+    current_pass_scanned: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScrubMetrics {
+    pub passes_completed: u64,
+    pub slices_scanned: u64,
+    pub bytes_scanned: u64,
+    pub corrupted_found: u64,
+    /// Slices found on a disk that went offline.
+    pub lost_with_disk: u64,
+    /// Replicated slices whose content was not on this node, e.g. not fetched when their entry was applied.
+    pub missing_found: u64,
+    pub repaired: u64,
+    pub repair_failed: u64,
+    pub shards_rebuilt: u64,
+    /// Blobs and shards deleted once nothing referred to them for the grace period.
+    pub garbage_collected: u64,
+    pub current_pass_scanned: u64,
+}
+
+impl ScrubStats {
+    pub fn metrics(&self) -> ScrubMetrics {
+        ScrubMetrics {
+            passes_completed: self.passes_completed.load(Ordering::Relaxed),
+            slices_scanned: self.slices_scanned.load(Ordering::Relaxed),
+            bytes_scanned: self.bytes_scanned.load(Ordering::Relaxed),
+            corrupted_found: self.corrupted_found.load(Ordering::Relaxed),
+            lost_with_disk: self.lost_with_disk.load(Ordering::Relaxed),
+            missing_found: self.missing_found.load(Ordering::Relaxed),
+            repaired: self.repaired.load(Ordering::Relaxed),
+            repair_failed: self.repair_failed.load(Ordering::Relaxed),
+            shards_rebuilt: self.shards_rebuilt.load(Ordering::Relaxed),
+            garbage_collected: self.garbage_collected.load(Ordering::Relaxed),
+            current_pass_scanned: self.current_pass_scanned.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Walks the slice backend forever, re-hashing every slice against its id.
+/// Corrupted slices are quarantined and re-fetched from another member of the raft group, as are slices lost with a disk.
+/// Shards of erasure coded slices this node should hold and does not are rebuilt from the other shards.
+/// Blobs and shards nothing refers to are deleted after `ARGS.gc_grace_period` seconds.
+/// Reads are throttled to `ARGS.scrub_bytes_per_second`, passes are `ARGS.scrub_interval` seconds apart.
+pub async fn run_scrubber(store: Arc<StorageNodeFileStore>, stats: Arc<ScrubStats>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(ARGS.scrub_interval)).await;
+
+        stats.current_pass_scanned.store(0, Ordering::Relaxed);
+        if let Err(err) = scrub_pass(&store, &stats).await {
+            tracing::error!("scrub pass aborted: {}", err);
+            continue;
+        }
+        stats.passes_completed.fetch_add(1, Ordering::Relaxed);
+        tracing::info!("scrub pass finished: {:?}", stats.metrics());
+    }
+}
+
+async fn scrub_pass(store: &Arc<StorageNodeFileStore>, stats: &ScrubStats) -> io::Result<()> {
+    let backend = store.backend.clone();
+    let mut ids = run_blocking(move || backend.list()).await?;
+    loop {
+        // Pull ids in batches, listing walks the disk too.
+        let (rest, batch) = run_blocking(move || {
+            let batch: Vec<io::Result<String>> = ids.by_ref().take(LIST_BATCH).collect();
+            Ok((ids, batch))
+        }).await?;
+        ids = rest;
+        if batch.is_empty() {
+            break;
+        }
+        for id in batch {
+            let id = id?;
+            if collect_garbage(store, stats, &id).await {
+                continue;
+            }
+            scrub_slice(store, stats, id).await;
+        }
+    }
+    check_shards(store, stats).await;
+    check_blobs(store, stats).await;
+    Ok(())
+}
+
+/// Deletes blobs no slice refers to and shards that are part of no layout placing them here,
+/// once they have been so for the grace period: content of deleted slices, and of writes that failed
+/// before their entry was committed. Returns whether `id` is such garbage, it is not scrubbed then.
+async fn collect_garbage(store: &Arc<StorageNodeFileStore>, stats: &ScrubStats, id: &str) -> bool {
+    // Held until it is deleted, an entry referring to it again is not applied meanwhile.
+    let state_machine = store.state_machine.read().await;
+    let garbage = match parse_shard_id(id) {
+        Some((slice_id, _)) => state_machine.slice(slice_id).map(|meta| {
+            let layout = meta.and_then(|meta| meta.erasure);
+            !layout.map_or(false, |layout| layout.local_shards(ARGS.node_id).any(|shard| shard.id == id))
+        }),
+        None if is_blob_id(id) => state_machine.is_unreferenced(id),
+        None => return false,
+    };
+    match garbage {
+        Ok(true) => {}
+        Ok(false) => {
+            store.collector.keep(id);
+            return false;
+        }
+        Err(err) => {
+            tracing::error!("failed to look up what refers to {}: {}", id, err);
+            return false;
+        }
+    }
+
+    let collecting = store.clone();
+    let collect_id = id.to_string();
+    match run_blocking(move || collecting.collector.collect(&*collecting.backend, &collect_id)).await {
+        Ok(true) => {
+            stats.garbage_collected.fetch_add(1, Ordering::Relaxed);
+            tracing::info!("scrubber deleted {}, nothing referred to it", id);
+        }
+        Ok(false) => {}
+        Err(err) => tracing::error!("failed to delete {}: {}", id, err),
+    }
+    drop(state_machine);
+    true
+}
+
+/// Fetches the content of replicated slices that is not on this node, from the other members.
+async fn check_blobs(store: &Arc<StorageNodeFileStore>, stats: &ScrubStats) {
+    let blobs: io::Result<BTreeSet<_>> = {
+        let state_machine = store.state_machine.read().await;
+        state_machine.slices()
+                     .filter_map(|slice| match slice {
+                         Ok((id, meta)) => meta.erasure.is_none().then(|| blob_id(&id)).flatten().map(|blob| Ok((id, blob))),
+                         Err(err) => Some(Err(err)),
+                     })
+                     .collect()
+    };
+    let blobs = match blobs {
+        Ok(blobs) => blobs,
+        Err(err) => {
+            tracing::error!("scrubber could not list replicated slices: {}", err);
+            return;
+        }
+    };
+    for (id, blob) in blobs {
+        let backend = store.backend.clone();
+        let stat_ids = [blob.clone(), id.clone()];
+        let missing = run_blocking(move || {
+            Ok(stat_ids.iter().all(|id| matches!(backend.stat(id), Err(err) if err.kind() == io::ErrorKind::NotFound)))
+        }).await;
+        if !matches!(missing, Ok(true)) {
+            continue;
+        }
+        stats.missing_found.fetch_add(1, Ordering::Relaxed);
+        if repair_slice(store, &blob).await {
+            stats.repaired.fetch_add(1, Ordering::Relaxed);
+        } else {
+            stats.repair_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Rebuilds the local shards of erasure coded slices that are missing: quarantined by this pass,
+/// lost with a disk, or never received because the node was down when the slice was written.
+async fn check_shards(store: &Arc<StorageNodeFileStore>, stats: &ScrubStats) {
+    let layouts: io::Result<Vec<_>> = {
+        let state_machine = store.state_machine.read().await;
+        state_machine.slices()
+                     .filter_map(|slice| match slice {
+                         Ok((id, meta)) => meta.erasure.map(|layout| Ok((id, layout))),
+                         Err(err) => Some(Err(err)),
+                     })
+                     .filter(|slice| slice.as_ref().map_or(true, |(_, layout)| layout.local_shards(ARGS.node_id).next().is_some()))
+                     .collect()
+    };
+    let layouts = match layouts {
+        Ok(layouts) => layouts,
+        Err(err) => {
+            tracing::error!("scrubber could not list erasure coded slices: {}", err);
+            return;
+        }
+    };
+    for (id, layout) in layouts {
+        match rebuild_local_shards(store, &layout).await {
+            Ok(rebuilt) => {
+                stats.shards_rebuilt.fetch_add(rebuilt as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                stats.repair_failed.fetch_add(1, Ordering::Relaxed);
+                tracing::error!("failed to rebuild shards of slice {}: {}", id, err);
+            }
+        }
+    }
+}
+
+async fn scrub_slice(store: &Arc<StorageNodeFileStore>, stats: &ScrubStats, id: String) {
+    let backend = store.backend.clone();
+    let verify_id = id.clone();
+    let result = run_blocking(move || backend.get_verified(&verify_id).map(|body| body.len())).await;
+
+    match result {
+        Ok(size) => {
+            stats.slices_scanned.fetch_add(1, Ordering::Relaxed);
+            stats.current_pass_scanned.fetch_add(1, Ordering::Relaxed);
+            stats.bytes_scanned.fetch_add(size as u64, Ordering::Relaxed);
+            throttle(size, ARGS.scrub_bytes_per_second).await;
+        }
+        Err(err) if is_corrupted(&err) => {
+            stats.corrupted_found.fetch_add(1, Ordering::Relaxed);
+            tracing::error!("scrubber found corrupted slice: {}", err);
+
+            let backend = store.backend.clone();
+            let quarantine_id = id.clone();
+            if let Err(err) = run_blocking(move || backend.quarantine(&quarantine_id)).await {
+                tracing::error!("failed to quarantine slice {}: {}", id, err);
+            }
+
+            // Other nodes hold other shards, `check_shards` rebuilds it at the end of the pass.
+            if parse_shard_id(&id).is_some() {
+                return;
+            }
+            if repair_slice(store, &id).await {
+                stats.repaired.fetch_add(1, Ordering::Relaxed);
+            } else {
+                stats.repair_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Err(err) if is_on_offline_disk(&err) => {
+            stats.lost_with_disk.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("scrubber found lost slice: {}", err);
+
+            if parse_shard_id(&id).is_some() {
+                return;
+            }
+            if repair_slice(store, &id).await {
+                stats.repaired.fetch_add(1, Ordering::Relaxed);
+            } else {
+                stats.repair_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // The slice may have been removed since it was listed.
+        Err(err) => tracing::warn!("scrubber could not read slice {}: {}", id, err),
+    }
+}
+
+/// Fetches a verified copy of slice `id` from the other members of the raft group.
+async fn repair_slice(store: &Arc<StorageNodeFileStore>, id: &str) -> bool {
+    let peers = {
+        let state_machine = store.state_machine.read().await;
+        // Tombstones are purged with the log, a slice the state machine no longer lists is deleted all the same.
+        let deleted = state_machine.is_tombstoned(id).and_then(|tombstoned| Ok(tombstoned || match is_blob_id(id) {
+            true => state_machine.is_unreferenced(id)?,
+            false => state_machine.slice(id)?.is_none(),
+        }));
+        match deleted {
+            Ok(true) => {
+                tracing::info!("slice {} is deleted, not repairing it", id);
+                return true;
+            }
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!("failed to look up slice {}: {}", id, err);
+                return false;
+            }
+        }
+        state_machine.peer_addrs(ARGS.node_id)
+    };
+    for addr in peers {
+        let body = match peer::fetch(&addr, "slice", id).await {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::warn!("failed to fetch slice {} from {}: {}", id, addr, err);
+                continue;
+            }
+        };
+
+        // A bad copy from the peer is rejected here.
+        let backend = store.backend.clone();
+        let store_id = id.to_string();
+        match run_blocking(move || backend.put_verified(&store_id, &body)).await {
+            Ok(()) => {
+                tracing::info!("slice {} repaired from {}", id, addr);
+                return true;
+            }
+            Err(err) => tracing::warn!("copy of slice {} from {} rejected: {}", id, addr, err),
+        }
+    }
+
+    tracing::error!("no peer could provide a good copy of slice {}", id);
+    false
+}
+
+/// Runs blocking file system work off the async executor so raft and http are not stalled.
+pub(crate) async fn run_blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
@@ -0,0 +1,553 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use openraft::{EffectiveMembership, LogId, SnapshotMeta};
+use serde::{Deserialize, Serialize};
+
+use crate::{ARGS, StorageNodeId};
+use crate::store::SliceMeta;
+use crate::store::dedup::content_digest;
+
+/// Snapshots start with this, followed by the length of their `SnapshotHeader` (big endian u64), the header,
+/// and the entries of the state machine trees one by one, see `write_entry`. Slices are referred to by their id,
+/// which holds their digest: a node installing the snapshot fetches the content it lacks from the other members.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"SNSNAP3\n";
+
+/// Snapshots of the previous version carried the state machine as a whole after its length, as one JSON document.
+const SNAPSHOT_WHOLE_MAGIC: &[u8; 8] = b"SNSNAP2\n";
+
+/// Snapshots of earlier versions carried the content of the slices after the state machine, each as
+/// the length of its id (u32), the id, the length of its content (u64) and the content, up to a zero id length.
+const SNAPSHOT_WITH_FILES_MAGIC: &[u8; 8] = b"SNSNAP1\n";
+
+/// Ends the entries of a snapshot, in place of an `EntryKind`.
+const END_OF_ENTRIES: u8 = 0;
+
+/// The state machine but its trees, at the start of a snapshot.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SnapshotHeader {
+    pub last_applied_log: Option<LogId<StorageNodeId>>,
+
+    pub last_membership: EffectiveMembership<StorageNodeId>,
+
+    pub nodemap_version: i64,
+}
+
+/// Tree of a snapshot entry. Snapshots carry the slices first, then the tombstones and the references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    Slice = 1,
+    Tombstone = 2,
+    Reference = 3,
+}
+
+/// An entry of the state machine trees, as snapshots carry it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotEntry {
+    /// A slice id and its metadata.
+    Slice(String, SliceMeta),
+    /// A deleted slice id and the log index of the deletion.
+    Tombstone(String, u64),
+    /// A content digest and the number of replicated slices referencing it.
+    Reference(String, u64),
+}
+
+impl SnapshotEntry {
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            SnapshotEntry::Slice(..) => EntryKind::Slice,
+            SnapshotEntry::Tombstone(..) => EntryKind::Tombstone,
+            SnapshotEntry::Reference(..) => EntryKind::Reference,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            SnapshotEntry::Slice(key, _) | SnapshotEntry::Tombstone(key, _) | SnapshotEntry::Reference(key, _) => key,
+        }
+    }
+}
+
+/// Directory under `storage_location` where snapshots are spooled, those built here as well as those received.
+pub const SNAPSHOT_DIRECTORY: &str = "snapshots";
+
+pub fn snapshot_directory() -> PathBuf {
+    Path::new(&ARGS.storage_location).join(SNAPSHOT_DIRECTORY)
+}
+
+/// File of snapshot `name`, a snapshot id or the name of a snapshot being received.
+pub fn snapshot_path(name: &str) -> PathBuf {
+    snapshot_directory().join(format!("{}.snap", name))
+}
+
+/// `SnapshotMeta` of snapshot `snapshot_id` as JSON, written once the snapshot file is complete.
+pub fn snapshot_meta_path(snapshot_id: &str) -> PathBuf {
+    snapshot_directory().join(format!("{}.meta", snapshot_id))
+}
+
+/// Where `path` is written before it is renamed into place.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Writes the meta of a snapshot whose file is complete, the snapshot is reloaded on the next start.
+pub fn save_snapshot_meta(meta: &SnapshotMeta<StorageNodeId>) -> io::Result<()> {
+    let path = snapshot_meta_path(&meta.snapshot_id);
+    let partial = partial_path(&path);
+    let data = serde_json::to_vec(meta).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut file = fs::File::create(&partial)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&partial, &path)?;
+    fs::File::open(snapshot_directory())?.sync_all()
+}
+
+/// Removes the file and the meta of snapshot `snapshot_id`.
+pub fn remove_snapshot(snapshot_id: &str) -> io::Result<()> {
+    for path in [snapshot_meta_path(snapshot_id), snapshot_path(snapshot_id)] {
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Finds the latest complete snapshot in the snapshot directory, on start.
+/// Everything else there is removed: older snapshots, and those left half written or half received.
+pub fn load_latest_snapshot() -> io::Result<Option<(SnapshotMeta<StorageNodeId>, PathBuf)>> {
+    let mut latest: Option<SnapshotMeta<StorageNodeId>> = None;
+    for entry in fs::read_dir(snapshot_directory())? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |extension| extension != "meta") {
+            continue;
+        }
+        let meta: SnapshotMeta<StorageNodeId> = match fs::read(&path).map(|data| serde_json::from_slice(&data)) {
+            Ok(Ok(meta)) => meta,
+            Ok(Err(err)) => {
+                tracing::warn!("ignoring unreadable snapshot meta {}: {}", path.display(), err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if !snapshot_path(&meta.snapshot_id).is_file() {
+            continue;
+        }
+        if latest.as_ref().map_or(true, |latest| meta.last_log_id > latest.last_log_id) {
+            latest = Some(meta);
+        }
+    }
+
+    let keep = latest.as_ref().map(|meta| (snapshot_path(&meta.snapshot_id), snapshot_meta_path(&meta.snapshot_id)));
+    for entry in fs::read_dir(snapshot_directory())? {
+        let path = entry?.path();
+        if keep.as_ref().map_or(false, |(data, meta)| path == *data || path == *meta) {
+            continue;
+        }
+        tracing::info!("removing stale snapshot file {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    Ok(latest.map(|meta| {
+        let path = snapshot_path(&meta.snapshot_id);
+        (meta, path)
+    }))
+}
+
+/// Writes a snapshot of the state machine `header` and `entries` belong to, the entries in the order of its trees.
+/// They are written as they come, the state machine is never held in memory as a whole.
+pub fn write_snapshot<W: Write>(
+    out: &mut W,
+    header: &SnapshotHeader,
+    entries: impl Iterator<Item = io::Result<SnapshotEntry>>,
+) -> io::Result<()> {
+    let header = serde_json::to_vec(header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&(header.len() as u64).to_be_bytes())?;
+    out.write_all(&header)?;
+    for entry in entries {
+        write_entry(out, &entry?)?;
+    }
+    out.write_all(&[END_OF_ENTRIES])?;
+    out.flush()
+}
+
+/// Writes `entry` as its kind (u8), the length of its key (u32), the key, the length of its value (u32)
+/// and the value: the `SliceMeta` as JSON, or a big endian u64, as the trees keep them.
+fn write_entry<W: Write>(out: &mut W, entry: &SnapshotEntry) -> io::Result<()> {
+    let value = match entry {
+        SnapshotEntry::Slice(_, meta) => serde_json::to_vec(meta).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        SnapshotEntry::Tombstone(_, number) | SnapshotEntry::Reference(_, number) => number.to_be_bytes().to_vec(),
+    };
+    out.write_all(&[entry.kind() as u8])?;
+    out.write_all(&(entry.key().len() as u32).to_be_bytes())?;
+    out.write_all(entry.key().as_bytes())?;
+    out.write_all(&(value.len() as u32).to_be_bytes())?;
+    out.write_all(&value)
+}
+
+/// Reads a snapshot written by `write_snapshot`, or by an earlier version.
+pub struct SnapshotReader<R> {
+    input: R,
+    /// Entries of a snapshot of an earlier version, which carried the state machine as a whole.
+    whole: Option<std::vec::IntoIter<SnapshotEntry>>,
+    /// The entry `holds` stopped at.
+    peeked: Option<SnapshotEntry>,
+    entries_done: bool,
+    files_done: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Reads the header at the start of `input`, the entries follow through `next_entry`. Snapshots
+    /// of earlier versions carrying slice content give it through `next_file`.
+    pub fn open(mut input: R) -> io::Result<(SnapshotHeader, Self)> {
+        let mut magic = [0u8; 8];
+        let read = read_up_to(&mut input, &mut magic)?;
+        let files_done = match &magic[..read] {
+            header if header == SNAPSHOT_MAGIC => {
+                let length = read_u64(&mut input)?;
+                let header = read_exact_vec(&mut input, length)?;
+                let header = serde_json::from_slice(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let reader = SnapshotReader { input, whole: None, peeked: None, entries_done: false, files_done: true };
+                return Ok((header, reader));
+            }
+            header if header == SNAPSHOT_WHOLE_MAGIC => true,
+            header if header == SNAPSHOT_WITH_FILES_MAGIC => false,
+            header => {
+                // A bare state machine, from before snapshots had a header.
+                let mut state_machine = header.to_vec();
+                input.read_to_end(&mut state_machine)?;
+                let (header, entries) = decode(&state_machine)?.split();
+                let reader = SnapshotReader { input, whole: Some(entries.into_iter()), peeked: None, entries_done: true, files_done: true };
+                return Ok((header, reader));
+            }
+        };
+
+        let length = read_u64(&mut input)?;
+        let state_machine = read_exact_vec(&mut input, length)?;
+        let (header, entries) = decode(&state_machine)?.split();
+        Ok((header, SnapshotReader { input, whole: Some(entries.into_iter()), peeked: None, entries_done: true, files_done }))
+    }
+
+    /// The next entry of the state machine trees, None after the last one.
+    pub fn next_entry(&mut self) -> io::Result<Option<SnapshotEntry>> {
+        if let Some(entry) = self.peeked.take() {
+            return Ok(Some(entry));
+        }
+        if let Some(whole) = &mut self.whole {
+            return Ok(whole.next());
+        }
+        if self.entries_done {
+            return Ok(None);
+        }
+        let mut kind = [0u8; 1];
+        self.input.read_exact(&mut kind)?;
+        if kind[0] == END_OF_ENTRIES {
+            self.entries_done = true;
+            return Ok(None);
+        }
+        let length = read_u32(&mut self.input)?;
+        let key = String::from_utf8(read_exact_vec(&mut self.input, length)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let length = read_u32(&mut self.input)?;
+        let value = read_exact_vec(&mut self.input, length)?;
+        let number = || -> io::Result<u64> {
+            let bytes: [u8; 8] = value[..].try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed snapshot counter"))?;
+            Ok(u64::from_be_bytes(bytes))
+        };
+        let entry = match kind[0] {
+            kind if kind == EntryKind::Slice as u8 => SnapshotEntry::Slice(
+                key,
+                serde_json::from_slice(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            ),
+            kind if kind == EntryKind::Tombstone as u8 => SnapshotEntry::Tombstone(key, number()?),
+            kind if kind == EntryKind::Reference as u8 => SnapshotEntry::Reference(key, number()?),
+            kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown snapshot entry kind {}", kind))),
+        };
+        Ok(Some(entry))
+    }
+
+    /// The entries left, one by one.
+    pub fn entries(&mut self) -> impl Iterator<Item = io::Result<SnapshotEntry>> + '_ {
+        std::iter::from_fn(move || self.next_entry().transpose())
+    }
+
+    /// Skips the entries before `key` of `kind` and tells whether the snapshot holds it. The state machine
+    /// trees are in the order of the snapshot, they are walked side by side with it.
+    pub fn holds(&mut self, kind: EntryKind, key: &str) -> io::Result<bool> {
+        while let Some(entry) = self.next_entry()? {
+            match (entry.kind(), entry.key()).cmp(&(kind, key)) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(true),
+                Ordering::Greater => {
+                    self.peeked = Some(entry);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// The next slice file of a snapshot of an earlier version, its id and content.
+    pub fn next_file(&mut self) -> io::Result<Option<(String, Vec<u8>)>> {
+        if self.files_done {
+            return Ok(None);
+        }
+        let length = read_u32(&mut self.input)?;
+        if length == 0 {
+            self.files_done = true;
+            return Ok(None);
+        }
+        let id = String::from_utf8(read_exact_vec(&mut self.input, length)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let length = read_u64(&mut self.input)?;
+        let body = read_exact_vec(&mut self.input, length)?;
+        Ok(Some((id, body)))
+    }
+}
+
+/// The state machine as a whole, as snapshots of earlier versions carried it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct StateMachineData {
+    last_applied_log: Option<LogId<StorageNodeId>>,
+
+    last_membership: EffectiveMembership<StorageNodeId>,
+
+    data: BTreeMap<String, SliceMeta>,
+
+    #[serde(default)]
+    tombstones: BTreeMap<String, u64>,
+
+    #[serde(default)]
+    refcounts: BTreeMap<String, u64>,
+
+    nodemap_version: i64,
+}
+
+impl StateMachineData {
+    /// Rebuilds reference counts from the slices, for snapshots taken before they were kept.
+    fn count_references(&mut self) {
+        let mut refcounts = BTreeMap::new();
+        for id in self.data.iter().filter(|(_, meta)| meta.erasure.is_none()).map(|(id, _)| id) {
+            *refcounts.entry(content_digest(id).unwrap_or_default()).or_insert(0) += 1;
+        }
+        self.refcounts = refcounts;
+    }
+
+    /// The header and the entries, in the order of the trees.
+    fn split(mut self) -> (SnapshotHeader, Vec<SnapshotEntry>) {
+        if self.refcounts.is_empty() {
+            self.count_references();
+        }
+        let header = SnapshotHeader {
+            last_applied_log: self.last_applied_log,
+            last_membership: self.last_membership,
+            nodemap_version: self.nodemap_version,
+        };
+        let entries = self.data.into_iter().map(|(id, meta)| SnapshotEntry::Slice(id, meta))
+            .chain(self.tombstones.into_iter().map(|(id, index)| SnapshotEntry::Tombstone(id, index)))
+            .chain(self.refcounts.into_iter().map(|(digest, count)| SnapshotEntry::Reference(digest, count)))
+            .collect();
+        (header, entries)
+    }
+}
+
+/// The state machine of the first versions, slices were only listed by id and stored under it.
+#[derive(Deserialize)]
+struct LegacyStateMachineData {
+    last_applied_log: Option<LogId<StorageNodeId>>,
+    last_membership: EffectiveMembership<StorageNodeId>,
+    data: Vec<String>,
+    nodemap_version: i64,
+}
+
+impl From<LegacyStateMachineData> for StateMachineData {
+    /// Sizes and write times were not kept, the slices are taken as written by the last applied entry.
+    fn from(legacy: LegacyStateMachineData) -> Self {
+        let log_index = legacy.last_applied_log.map_or(0, |log_id| log_id.index);
+        StateMachineData {
+            last_applied_log: legacy.last_applied_log,
+            last_membership: legacy.last_membership,
+            data: legacy.data
+                .into_iter()
+                .map(|id| (id, SliceMeta { size: 0, log_index, written_at: 0, erasure: None }))
+                .collect(),
+            nodemap_version: legacy.nodemap_version,
+            ..Default::default()
+        }
+    }
+}
+
+fn decode(state_machine: &[u8]) -> io::Result<StateMachineData> {
+    serde_json::from_slice(state_machine).or_else(|err| {
+        serde_json::from_slice::<LegacyStateMachineData>(state_machine)
+            .map(StateMachineData::from)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, err))
+    })
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes) as u64)
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_exact_vec<R: Read>(input: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot ends early"));
+    }
+    Ok(bytes)
+}
+
+/// Fills `buf` as far as `input` goes, returns how much was read.
+fn read_up_to<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn slice_id(name: &str) -> String {
+        format!("{}.{}", "ab".repeat(32), name)
+    }
+
+    fn entries() -> Vec<SnapshotEntry> {
+        vec![
+            SnapshotEntry::Slice(slice_id("one"), SliceMeta { size: 3, log_index: 7, written_at: 1, erasure: None }),
+            SnapshotEntry::Slice(slice_id("two"), SliceMeta { size: 3, log_index: 8, written_at: 2, erasure: None }),
+            SnapshotEntry::Tombstone(slice_id("three"), 9),
+            SnapshotEntry::Reference("ab".repeat(32), 2),
+        ]
+    }
+
+    fn snapshot() -> Vec<u8> {
+        let header = SnapshotHeader { nodemap_version: 4, ..Default::default() };
+        let mut snapshot = Vec::new();
+        write_snapshot(&mut snapshot, &header, entries().into_iter().map(Ok)).unwrap();
+        snapshot
+    }
+
+    fn whole() -> StateMachineData {
+        let mut data = StateMachineData::default();
+        data.data.insert(slice_id("one"), SliceMeta { size: 3, log_index: 7, written_at: 1, erasure: None });
+        data
+    }
+
+    #[test]
+    fn snapshot_round_trips_entry_by_entry() {
+        let snapshot = snapshot();
+        assert!(snapshot.starts_with(SNAPSHOT_MAGIC));
+
+        let (header, mut reader) = SnapshotReader::open(&snapshot[..]).unwrap();
+        assert_eq!(header.nodemap_version, 4);
+        assert_eq!(reader.entries().collect::<io::Result<Vec<_>>>().unwrap(), entries());
+        assert!(reader.next_entry().unwrap().is_none());
+        assert!(reader.next_file().unwrap().is_none());
+    }
+
+    #[test]
+    fn holds_walks_the_entries_in_order() {
+        let snapshot = snapshot();
+        let (_, mut reader) = SnapshotReader::open(&snapshot[..]).unwrap();
+        assert!(!reader.holds(EntryKind::Slice, &slice_id("a")).unwrap());
+        assert!(reader.holds(EntryKind::Slice, &slice_id("one")).unwrap());
+        assert!(!reader.holds(EntryKind::Slice, &slice_id("three")).unwrap());
+        assert!(reader.holds(EntryKind::Slice, &slice_id("two")).unwrap());
+        assert!(!reader.holds(EntryKind::Reference, &"aa".repeat(32)).unwrap());
+        assert!(reader.holds(EntryKind::Reference, &"ab".repeat(32)).unwrap());
+        assert!(!reader.holds(EntryKind::Reference, &"ac".repeat(32)).unwrap());
+    }
+
+    #[test]
+    fn whole_state_machine_is_read_as_entries() {
+        let state_machine = serde_json::to_vec(&whole()).unwrap();
+        let mut snapshot = SNAPSHOT_WHOLE_MAGIC.to_vec();
+        snapshot.extend_from_slice(&(state_machine.len() as u64).to_be_bytes());
+        snapshot.extend_from_slice(&state_machine);
+
+        let (_, mut reader) = SnapshotReader::open(&snapshot[..]).unwrap();
+        assert_eq!(reader.entries().collect::<io::Result<Vec<_>>>().unwrap(), vec![
+            SnapshotEntry::Slice(slice_id("one"), SliceMeta { size: 3, log_index: 7, written_at: 1, erasure: None }),
+            // Counted, the snapshot was taken before references were kept.
+            SnapshotEntry::Reference("ab".repeat(32), 1),
+        ]);
+        assert!(reader.next_file().unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_with_files_gives_them() {
+        let state_machine = serde_json::to_vec(&whole()).unwrap();
+        let mut snapshot = SNAPSHOT_WITH_FILES_MAGIC.to_vec();
+        snapshot.extend_from_slice(&(state_machine.len() as u64).to_be_bytes());
+        snapshot.extend_from_slice(&state_machine);
+        let id = slice_id("one");
+        snapshot.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        snapshot.extend_from_slice(id.as_bytes());
+        snapshot.extend_from_slice(&3u64.to_be_bytes());
+        snapshot.extend_from_slice(b"one");
+        snapshot.extend_from_slice(&0u32.to_be_bytes());
+
+        let (_, mut files) = SnapshotReader::open(&snapshot[..]).unwrap();
+        assert_eq!(files.next_file().unwrap(), Some((id.clone(), b"one".to_vec())));
+        assert!(files.next_file().unwrap().is_none());
+        assert!(files.holds(EntryKind::Slice, &id).unwrap());
+
+        // Cut within the content.
+        let (_, mut files) = SnapshotReader::open(&snapshot[..snapshot.len() - 6]).unwrap();
+        assert_eq!(files.next_file().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_snapshot_is_refused() {
+        let snapshot = snapshot();
+        let (_, mut reader) = SnapshotReader::open(&snapshot[..snapshot.len() - 1]).unwrap();
+        let error = reader.entries().collect::<io::Result<Vec<_>>>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = SnapshotReader::open(&snapshot[..20]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = SnapshotReader::open(&b"not a snapshot"[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn legacy_state_machine_lists_slice_ids() {
+        let legacy = json!({
+            "last_applied_log": null,
+            "last_membership": EffectiveMembership::<StorageNodeId>::default(),
+            "data": [slice_id("one"), slice_id("two")],
+            "nodemap_version": 3,
+        });
+        let (header, mut reader) = SnapshotReader::open(legacy.to_string().as_bytes()).unwrap();
+        let entries = reader.entries().collect::<io::Result<Vec<_>>>().unwrap();
+        let slices: Vec<_> = entries.iter().filter(|entry| entry.kind() == EntryKind::Slice).map(|entry| entry.key()).collect();
+        assert_eq!(slices, vec![slice_id("one"), slice_id("two")]);
+        assert!(entries.iter().all(|entry| !matches!(entry, SnapshotEntry::Slice(_, meta) if meta.erasure.is_some())));
+        assert_eq!(entries.last(), Some(&SnapshotEntry::Reference("ab".repeat(32), 2)));
+        assert_eq!(header.nodemap_version, 3);
+        assert!(reader.next_file().unwrap().is_none());
+    }
+}
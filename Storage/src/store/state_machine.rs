@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+
+use openraft::{EffectiveMembership, LogId};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sled::{Batch, Db, Tree};
+
+use crate::StorageNodeId;
+use crate::store::SliceMeta;
+use crate::store::dedup::{content_digest, DedupMetrics};
+use crate::store::snapshot::{SnapshotEntry, SnapshotHeader};
+
+/// Slice ids and their `SliceMeta`.
+const SLICE_TREE: &str = "state-machine-slices";
+/// Deleted slice ids and the log index of the deletion, big endian.
+const TOMBSTONE_TREE: &str = "state-machine-tombstones";
+/// Content digests and the number of replicated slices referencing them, big endian.
+const REFCOUNT_TREE: &str = "state-machine-refcounts";
+/// Last applied log id, last membership and nodemap version.
+const META_TREE: &str = "state-machine-meta";
+
+const LAST_APPLIED_LOG: &str = "last-applied-log";
+const LAST_MEMBERSHIP: &str = "last-membership";
+const NODEMAP_VERSION: &str = "nodemap-version";
+/// Id of the snapshot `replace` is installing, present until it is done.
+const INSTALLING_SNAPSHOT: &str = "installing-snapshot";
+/// Entries `replace` writes to each tree at once.
+const REPLACE_BATCH: usize = 10_000;
+
+/**
+ * Here defines a state machine of the raft, this state represents a copy of the data
+ * between each node. Slices, tombstones and reference counts live in sled trees keyed by id,
+ * so that the state machine survives restarts and lookups do not scan it.
+ * The last applied log id, the membership and the nodemap version are kept in memory as well,
+ * they are written along when `save_applied` is called at the end of an apply.
+ */
+#[derive(Debug, Clone)]
+pub struct StorageNodeStoreStateMachine {
+    pub last_applied_log: Option<LogId<StorageNodeId>>,
+
+    // TODO: it should not be Option.
+    pub last_membership: EffectiveMembership<StorageNodeId>,
+
+    pub nodemap_version: i64,
+
+    slices: Tree,
+    tombstones: Tree,
+    refcounts: Tree,
+    meta: Tree,
+}
+
+fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_u64(bytes: &[u8]) -> io::Result<u64> {
+    let bytes: [u8; 8] = bytes.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed state machine counter"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn decode_id(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl StorageNodeStoreStateMachine {
+    /// Opens the state machine persisted in `db`, empty on the first start.
+    pub fn open(db: &Db) -> io::Result<Self> {
+        let meta = db.open_tree(META_TREE)?;
+        let read = |key: &str| -> io::Result<Option<sled::IVec>> { Ok(meta.get(key)?) };
+        let last_applied_log = read(LAST_APPLIED_LOG)?.map(|value| decode(&value)).transpose()?.flatten();
+        let last_membership = read(LAST_MEMBERSHIP)?.map(|value| decode(&value)).transpose()?.unwrap_or_default();
+        let nodemap_version = read(NODEMAP_VERSION)?.map(|value| decode(&value)).transpose()?.unwrap_or_default();
+
+        Ok(StorageNodeStoreStateMachine {
+            last_applied_log,
+            last_membership,
+            nodemap_version,
+            slices: db.open_tree(SLICE_TREE)?,
+            tombstones: db.open_tree(TOMBSTONE_TREE)?,
+            refcounts: db.open_tree(REFCOUNT_TREE)?,
+            meta,
+        })
+    }
+
+    /// Persists the last applied log id, the membership and the nodemap version, and flushes
+    /// everything applied so far. Entries applied after the last call are applied again after a crash,
+    /// applying an entry twice leaves the state machine as applying it once.
+    pub fn save_applied(&self) -> io::Result<()> {
+        let mut batch = Batch::default();
+        batch.insert(LAST_APPLIED_LOG, encode(&self.last_applied_log)?);
+        batch.insert(LAST_MEMBERSHIP, encode(&self.last_membership)?);
+        batch.insert(NODEMAP_VERSION, encode(&self.nodemap_version)?);
+        self.meta.apply_batch(batch)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    pub fn slice(&self, id: &str) -> io::Result<Option<SliceMeta>> {
+        self.slices.get(id)?.map(|value| decode(&value)).transpose()
+    }
+
+    pub fn insert_slice(&self, id: &str, meta: &SliceMeta) -> io::Result<Option<SliceMeta>> {
+        self.slices.insert(id, encode(meta)?)?.map(|value| decode(&value)).transpose()
+    }
+
+    pub fn remove_slice(&self, id: &str) -> io::Result<Option<SliceMeta>> {
+        self.slices.remove(id)?.map(|value| decode(&value)).transpose()
+    }
+
+    /// All slices in id order.
+    pub fn slices(&self) -> impl Iterator<Item = io::Result<(String, SliceMeta)>> {
+        self.slices.iter().map(|entry| {
+            let (id, value) = entry?;
+            Ok((decode_id(&id)?, decode(&value)?))
+        })
+    }
+
+    /// Up to `limit` slices whose id starts with `prefix`, in id order, after `start_after` if given.
+    /// Also tells whether more slices follow.
+    pub fn list(&self, prefix: &str, start_after: Option<&str>, limit: usize) -> io::Result<(Vec<(String, SliceMeta)>, bool)> {
+        let start = match start_after {
+            Some(start_after) if start_after >= prefix => Bound::Excluded(start_after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut slices = Vec::with_capacity(limit.min(1024) + 1);
+        for entry in self.slices.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let (id, value) = entry?;
+            if !id.starts_with(prefix.as_bytes()) || slices.len() > limit {
+                break;
+            }
+            slices.push((decode_id(&id)?, decode(&value)?));
+        }
+        let truncated = slices.len() > limit;
+        slices.truncate(limit);
+        Ok((slices, truncated))
+    }
+
+    pub fn is_tombstoned(&self, id: &str) -> io::Result<bool> {
+        Ok(self.tombstones.contains_key(id)?)
+    }
+
+    pub fn tombstone(&self, id: &str, index: u64) -> io::Result<()> {
+        self.tombstones.insert(id, &index.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    pub fn remove_tombstone(&self, id: &str) -> io::Result<()> {
+        self.tombstones.remove(id)?;
+        Ok(())
+    }
+
+    /// Drops the tombstones of deletions at or before log index `upto`, returns how many.
+    pub fn purge_tombstones(&self, upto: u64) -> io::Result<usize> {
+        let mut batch = Batch::default();
+        let mut purged = 0;
+        for entry in self.tombstones.iter() {
+            let (id, index) = entry?;
+            if decode_u64(&index)? <= upto {
+                batch.remove(id);
+                purged += 1;
+            }
+        }
+        self.tombstones.apply_batch(batch)?;
+        Ok(purged)
+    }
+
+    /// Tells whether the blob behind slice or blob `id` is no longer referenced by any slice.
+    pub fn is_unreferenced(&self, id: &str) -> io::Result<bool> {
+        match content_digest(id) {
+            Some(digest) => Ok(!self.refcounts.contains_key(digest)?),
+            None => Ok(true),
+        }
+    }
+
+    /// Counts a new reference to the content of `id`, returns true for the first one.
+    pub fn add_reference(&self, id: &str) -> io::Result<bool> {
+        let digest = content_digest(id).unwrap_or_default();
+        let count = self.refcounts.get(&digest)?.map_or(Ok(0), |value| decode_u64(&value))? + 1;
+        self.refcounts.insert(digest, &count.to_be_bytes()[..])?;
+        Ok(count == 1)
+    }
+
+    /// Drops a reference to the content of `id`, returns true when it was the last one.
+    pub fn drop_reference(&self, id: &str) -> io::Result<bool> {
+        let digest = content_digest(id).unwrap_or_default();
+        match self.refcounts.get(&digest)?.map(|value| decode_u64(&value)).transpose()? {
+            Some(count) if count > 1 => {
+                self.refcounts.insert(digest, &(count - 1).to_be_bytes()[..])?;
+                Ok(false)
+            }
+            Some(_) => {
+                self.refcounts.remove(digest)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn dedup_metrics(&self) -> io::Result<DedupMetrics> {
+        let mut metrics = DedupMetrics::default();
+        let mut unique = BTreeMap::new();
+        for entry in self.slices() {
+            let (id, meta) = entry?;
+            if meta.erasure.is_some() {
+                continue;
+            }
+            metrics.references += 1;
+            metrics.logical_bytes += meta.size;
+            unique.insert(content_digest(&id).unwrap_or_default(), meta.size);
+        }
+        metrics.blobs = unique.len() as u64;
+        metrics.unique_bytes = unique.values().sum();
+        metrics.saved_bytes = metrics.logical_bytes - metrics.unique_bytes;
+        Ok(metrics)
+    }
+
+    /// Addresses of the other members of this raft group.
+    pub fn peer_addrs(&self, self_id: StorageNodeId) -> Vec<String> {
+        let membership = &self.last_membership.membership;
+        membership.all_members()
+                  .iter()
+                  .filter(|id| **id != self_id)
+                  .filter_map(|id| membership.get_node(id))
+                  .map(|node| node.addr.clone())
+                  .collect()
+    }
+
+    /// All reference counts in digest order.
+    pub fn references(&self) -> impl Iterator<Item = io::Result<(String, u64)>> {
+        self.refcounts.iter().map(|entry| {
+            let (digest, count) = entry?;
+            Ok((decode_id(&digest)?, decode_u64(&count)?))
+        })
+    }
+
+    /// The state machine but its trees, to build a snapshot.
+    pub fn header(&self) -> SnapshotHeader {
+        SnapshotHeader {
+            last_applied_log: self.last_applied_log,
+            last_membership: self.last_membership.clone(),
+            nodemap_version: self.nodemap_version,
+        }
+    }
+
+    /// The entries of the trees in snapshot order, read one by one to build a snapshot.
+    pub fn entries(&self) -> impl Iterator<Item = io::Result<SnapshotEntry>> {
+        let tombstones = self.tombstones.iter().map(|entry| {
+            let (id, index) = entry?;
+            Ok(SnapshotEntry::Tombstone(decode_id(&id)?, decode_u64(&index)?))
+        });
+        self.slices()
+            .map(|entry| entry.map(|(id, meta)| SnapshotEntry::Slice(id, meta)))
+            .chain(tombstones)
+            .chain(self.references().map(|entry| entry.map(|(digest, count)| SnapshotEntry::Reference(digest, count))))
+    }
+
+    /// Replaces the whole state machine with `header` and `entries`, from snapshot `snapshot_id`. The entries are
+    /// written in batches as they are read: a crash in between leaves `installing_snapshot` set,
+    /// and the snapshot is installed again on the next start.
+    pub fn replace(
+        &mut self,
+        header: SnapshotHeader,
+        entries: impl Iterator<Item = io::Result<SnapshotEntry>>,
+        snapshot_id: &str,
+    ) -> io::Result<()> {
+        self.meta.insert(INSTALLING_SNAPSHOT, snapshot_id.as_bytes())?;
+        self.meta.flush()?;
+
+        self.slices.clear()?;
+        self.tombstones.clear()?;
+        self.refcounts.clear()?;
+
+        let mut batches = [Batch::default(), Batch::default(), Batch::default()];
+        let mut pending = 0;
+        for entry in entries {
+            match entry? {
+                SnapshotEntry::Slice(id, meta) => batches[0].insert(id.as_bytes(), encode(&meta)?),
+                SnapshotEntry::Tombstone(id, index) => batches[1].insert(id.as_bytes(), &index.to_be_bytes()[..]),
+                SnapshotEntry::Reference(digest, count) => batches[2].insert(digest.as_bytes(), &count.to_be_bytes()[..]),
+            }
+            pending += 1;
+            if pending == REPLACE_BATCH {
+                self.apply_batches(&mut batches)?;
+                pending = 0;
+            }
+        }
+        self.apply_batches(&mut batches)?;
+
+        self.last_applied_log = header.last_applied_log;
+        self.last_membership = header.last_membership;
+        self.nodemap_version = header.nodemap_version;
+        self.save_applied()?;
+
+        self.meta.remove(INSTALLING_SNAPSHOT)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// Writes the slice, tombstone and reference batches of `replace`, and leaves them empty.
+    fn apply_batches(&self, batches: &mut [Batch; 3]) -> io::Result<()> {
+        for (tree, batch) in [&self.slices, &self.tombstones, &self.refcounts].into_iter().zip(batches.iter_mut()) {
+            tree.apply_batch(std::mem::take(batch))?;
+        }
+        Ok(())
+    }
+
+    /// The snapshot `replace` was installing when the node stopped, the trees hold part of it.
+    pub fn installing_snapshot(&self) -> io::Result<Option<String>> {
+        self.meta.get(INSTALLING_SNAPSHOT)?.map(|id| decode_id(&id)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(ids: &[&str]) -> impl Iterator<Item = io::Result<SnapshotEntry>> {
+        let mut entries = Vec::new();
+        for (index, id) in ids.iter().enumerate() {
+            entries.push(SnapshotEntry::Slice(id.to_string(), SliceMeta { size: 1, log_index: index as u64, written_at: 0, erasure: None }));
+        }
+        for id in ids {
+            entries.push(SnapshotEntry::Reference(content_digest(id).unwrap().to_string(), 1));
+        }
+        entries.into_iter().map(Ok)
+    }
+
+    #[test]
+    fn replace_swaps_the_whole_state_machine() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state_machine = StorageNodeStoreStateMachine::open(&db).unwrap();
+        let old = format!("{}.old", "a".repeat(64));
+        let new = format!("{}.new", "b".repeat(64));
+        state_machine.replace(SnapshotHeader::default(), entries(&[&old]), "first").unwrap();
+        state_machine.tombstone(&new, 1).unwrap();
+
+        state_machine.replace(SnapshotHeader::default(), entries(&[&new]), "second").unwrap();
+        assert_eq!(state_machine.installing_snapshot().unwrap(), None);
+        assert!(state_machine.slice(&old).unwrap().is_none());
+        assert!(state_machine.slice(&new).unwrap().is_some());
+        assert!(!state_machine.is_tombstoned(&new).unwrap());
+        assert!(state_machine.is_unreferenced(&old).unwrap());
+        assert!(!state_machine.is_unreferenced(&new).unwrap());
+    }
+
+    #[test]
+    fn entries_replace_an_other_state_machine() {
+        let ids: Vec<String> = (0..3).map(|index| format!("{}.{}", "c".repeat(64), index)).collect();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state_machine = StorageNodeStoreStateMachine::open(&db).unwrap();
+        state_machine.replace(SnapshotHeader { nodemap_version: 2, ..Default::default() }, entries(&[&ids[0], &ids[1], &ids[2]]), "first").unwrap();
+        state_machine.tombstone(&format!("{}.gone", "d".repeat(64)), 4).unwrap();
+
+        let other = sled::Config::new().temporary(true).open().unwrap();
+        let mut copy = StorageNodeStoreStateMachine::open(&other).unwrap();
+        copy.replace(state_machine.header(), state_machine.entries(), "copy").unwrap();
+        assert_eq!(copy.nodemap_version, 2);
+        assert_eq!(
+            copy.entries().collect::<io::Result<Vec<_>>>().unwrap(),
+            state_machine.entries().collect::<io::Result<Vec<_>>>().unwrap(),
+        );
+        assert_eq!(copy.references().collect::<io::Result<Vec<_>>>().unwrap(), vec![("c".repeat(64), 3)]);
+    }
+
+    #[test]
+    fn interrupted_replace_is_left_marked() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state_machine = StorageNodeStoreStateMachine::open(&db).unwrap();
+        // As `replace` leaves it when the node stops before the trees are rewritten.
+        state_machine.meta.insert(INSTALLING_SNAPSHOT, &b"1-2-3"[..]).unwrap();
+
+        let reopened = StorageNodeStoreStateMachine::open(&db).unwrap();
+        assert_eq!(reopened.installing_snapshot().unwrap().as_deref(), Some("1-2-3"));
+    }
+}
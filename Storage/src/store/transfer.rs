@@ -0,0 +1,183 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use openraft::Membership;
+use tokio::sync::mpsc;
+
+use crate::{ARGS, StorageNodeId};
+use crate::store::peer;
+use crate::store::scrub::run_blocking;
+use crate::store::StorageNodeFileStore;
+
+/// Pause after a round over the peers some of which could not be reached, doubled after each round up to
+/// `FETCH_MAX_BACKOFF`.
+const FETCH_BACKOFF: Duration = Duration::from_millis(100);
+const FETCH_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Blobs fetched at the same time, e.g. for a snapshot referring to many this node lacks.
+const FETCH_CONCURRENCY: usize = 32;
+
+/// The voters of `membership` other than this node with their addresses, `first` leading.
+pub fn member_nodes(membership: &Membership<StorageNodeId>, first: Option<StorageNodeId>) -> Vec<(StorageNodeId, String)> {
+    let mut peers: Vec<StorageNodeId> = membership.all_members().iter().cloned().filter(|id| *id != ARGS.node_id).collect();
+    if let Some(position) = first.and_then(|first| peers.iter().position(|id| *id == first)) {
+        let first = peers.remove(position);
+        peers.insert(0, first);
+    }
+    peers.into_iter().filter_map(|id| membership.get_node(&id).map(|node| (id, node.addr.clone()))).collect()
+}
+
+/// Their addresses only.
+pub fn member_addrs(membership: &Membership<StorageNodeId>, first: Option<StorageNodeId>) -> Vec<String> {
+    member_nodes(membership, first).into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Same, for the membership this node applied last.
+pub async fn members(store: &Arc<StorageNodeFileStore>, first: Option<StorageNodeId>) -> Vec<String> {
+    let state_machine = store.state_machine.read().await;
+    member_addrs(&state_machine.last_membership.membership, first)
+}
+
+/// Tells whether `nodes` hold a majority of the voters of every config, a joint membership has two.
+fn is_quorum(configs: &[BTreeSet<StorageNodeId>], nodes: &BTreeSet<StorageNodeId>) -> bool {
+    configs.iter().all(|voters| voters.iter().filter(|id| nodes.contains(id)).count() > voters.len() / 2)
+}
+
+/// Stores blob `id` on this node and sends it to the other voters at once, returning once a quorum
+/// of the voters holds it. The voters not counted yet keep receiving it in the background.
+pub async fn replicate_blob(store: &Arc<StorageNodeFileStore>, id: &str, body: Vec<u8>) -> io::Result<()> {
+    let (configs, peers) = {
+        let state_machine = store.state_machine.read().await;
+        let membership = &state_machine.last_membership.membership;
+        (membership.get_configs().clone(), member_nodes(membership, None))
+    };
+
+    // A blob stored already may be garbage since its last slice went,
+    // its grace period starts over and outlasts this write.
+    store.collector.keep(id);
+    let backend = store.backend.clone();
+    let local_id = id.to_string();
+    let local_body = body.clone();
+    run_blocking(move || match backend.stat(&local_id) {
+        Ok(_) => Ok(()),
+        Err(_) => backend.put_verified(&local_id, &local_body),
+    }).await?;
+    let mut stored = BTreeSet::from([ARGS.node_id]);
+
+    let (sender, mut pushed) = mpsc::unbounded_channel();
+    for (node, addr) in peers {
+        let (id, body, sender) = (id.to_string(), body.clone(), sender.clone());
+        tokio::spawn(async move {
+            let _ = sender.send((node, peer::store(&addr, "blob", &id, body).await));
+        });
+    }
+    drop(sender);
+    while !is_quorum(&configs, &stored) {
+        match pushed.recv().await {
+            Some((node, Ok(()))) => {
+                stored.insert(node);
+            }
+            Some((node, Err(err))) => tracing::warn!("sending blob {} to node {} failed: {}", id, node, err),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("blob {} is stored on nodes {:?}, a majority of each of {:?} is needed", id, stored, configs),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure blob `id` is stored on this node, fetching it from `peers`, in order. Rounds over the peers
+/// go on as long as some cannot be reached or fail to answer, a network blip only delays the caller.
+/// Gives up once every peer answers that it does not hold the blob or sends content not matching it.
+pub async fn ensure_blob(store: &Arc<StorageNodeFileStore>, id: &str, peers: &[String]) -> io::Result<()> {
+    store.collector.keep(id);
+    let backend = store.backend.clone();
+    let stat_id = id.to_string();
+    match run_blocking(move || backend.stat(&stat_id)).await {
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut backoff = FETCH_BACKOFF;
+    loop {
+        let mut unreachable = 0;
+        for addr in peers {
+            let body = match peer::fetch(addr, "blob", id).await {
+                Ok(body) => body,
+                Err(err) => {
+                    tracing::warn!("failed to fetch blob {} from {}: {}", id, addr, err);
+                    // Missing on the peer or corrupted on the way, asking again gets the same answer.
+                    if !matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::InvalidData) {
+                        unreachable += 1;
+                    }
+                    continue;
+                }
+            };
+            let backend = store.backend.clone();
+            let store_id = id.to_string();
+            return run_blocking(move || backend.put_verified(&store_id, &body)).await;
+        }
+        if unreachable == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no member could provide blob {}", id)));
+        }
+        tracing::warn!("{} members could not be asked for blob {}, asking again in {:?}", unreachable, id, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(FETCH_MAX_BACKOFF);
+    }
+}
+
+/// Makes sure every blob of `blobs` is stored on this node, fetching up to `FETCH_CONCURRENCY` at once.
+/// Returns those that are not, with why.
+pub async fn ensure_blobs(store: &Arc<StorageNodeFileStore>, blobs: Vec<String>, peers: Vec<String>) -> HashMap<String, io::Error> {
+    let peers = Arc::new(peers);
+    let mut missing = HashMap::new();
+    for batch in blobs.chunks(FETCH_CONCURRENCY) {
+        let fetches: Vec<_> = batch
+            .iter()
+            .map(|id| {
+                let (store, peers, fetch_id) = (store.clone(), peers.clone(), id.clone());
+                (id, tokio::spawn(async move { ensure_blob(&store, &fetch_id, &peers).await }))
+            })
+            .collect();
+        for (id, fetch) in fetches {
+            let result = fetch.await.map_err(|e| io::Error::new(io::ErrorKind::Other, e)).and_then(|result| result);
+            if let Err(err) = result {
+                missing.insert(id.clone(), err);
+            }
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(ids: &[StorageNodeId]) -> BTreeSet<StorageNodeId> {
+        ids.iter().cloned().collect()
+    }
+
+    #[test]
+    fn quorum_is_a_majority_of_the_voters() {
+        let configs = [nodes(&[1, 2, 3])];
+        assert!(!is_quorum(&configs, &nodes(&[1])));
+        assert!(is_quorum(&configs, &nodes(&[1, 3])));
+        assert!(!is_quorum(&[nodes(&[1, 2, 3, 4])], &nodes(&[1, 2])));
+        // Learners do not count.
+        assert!(!is_quorum(&configs, &nodes(&[1, 5, 6])));
+    }
+
+    #[test]
+    fn joint_quorum_needs_a_majority_of_each_config() {
+        let configs = [nodes(&[1, 2, 3]), nodes(&[3, 4, 5])];
+        assert!(!is_quorum(&configs, &nodes(&[1, 2, 4])));
+        assert!(is_quorum(&configs, &nodes(&[1, 3, 4])));
+        assert!(is_quorum(&configs, &nodes(&[2, 3, 5])));
+    }
+}
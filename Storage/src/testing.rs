@@ -2,9 +2,12 @@ use std::sync::Arc;
 use openraft::StorageError;
 use openraft::testing::Suite;
 use crate::{StorageNodeFileStore, StorageNodeId};
+use crate::store::backend::MemoryBackend;
+use crate::store::get_sled_db;
+use crate::store::health::NodeHealth;
 
 pub async fn new_async() -> Arc<StorageNodeFileStore> {
-    let res = StorageNodeFileStore::open_create(None, Some(()));
+    let res = StorageNodeFileStore::open_create(&get_sled_db().unwrap(), Arc::new(MemoryBackend::default()), None, Arc::new(NodeHealth::default())).unwrap();
 
     Arc::new(res)
 }